@@ -0,0 +1,15 @@
+use std::time;
+
+
+//where games read the time from, so something else than the system clock can drive them
+pub trait Clock: Send + Sync{
+    fn now(&self)->time::Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock{
+    fn now(&self)->time::Instant{
+        time::Instant::now()
+    }
+}
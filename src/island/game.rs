@@ -1,26 +1,37 @@
 // #![allow(unused)]
 
 
-use std::{collections::HashMap};
+use std::{collections::{HashMap, HashSet, VecDeque}};
+use std::sync::Arc;
 use std::time;
 use rocket::response::status::NotFound;
 use serde::Serialize;
 use tokio::sync::oneshot;
 use rand::Rng;
 use crate::{GameMessage, MoveResult, database};
+use super::rules::{AbandonRule, DisconnectRule, GameMode, GameRules, PawnVariant, SuddenDeath};
+use super::clock::Clock;
+use super::spectators::{Snapshot, Spectators};
+use super::pieces::{Piece, PieceDef, Terrain, registry, DIAGONALS, STRAIGHTS};
 
 const BOARD_SIZE : i32 = 15;
-const TREASURE_TILE : i32 = BOARD_SIZE*7+7;
+//home tiles of the treasures, the first one is used in single treasure games
+const TREASURE_SPOTS : [(i32,i32);5] = [(7,7),(7,3),(7,11),(11,7),(4,7)];
 const LAST_TILE : i32 = BOARD_SIZE * BOARD_SIZE;
-const VIEW_SIZE : i32 = 9 ;
-const VIEW_RADIUS: i32 =  VIEW_SIZE/2;
 const ENERGY_REGEN:f32 = 0.2;
-const DIAGONALS :[(i32, i32);4] = [(1,1),(-1,1),(1,-1),(-1,-1)];
-const STRAIGHTS : [(i32,i32);4] = [(1,0),(-1,0),(0,1),(0,-1)];
-const KNIGHTHOPS : [(i32,i32);8] = [(1,2),(2,1),(1,-2),(2,-1),(-1,-2),(-2,-1),(-1,2),(-2,1)];
-const MINPLAYERCOUNT : i32 = 2;
-const MAXPLAYERCOUNT : i32 = 6;
-const MOVE_DELAY: f32 = 0.15;
+const MAX_ENERGY:f32 = 10.;
+//seconds per game tick
+pub const TICK:f32 = 0.1;
+//ticks between the state updates everyone gets, even if nothing moved
+const STATE_TICKS:u64 = 10;
+pub const MINPLAYERCOUNT : i32 = 2;
+pub const MAXPLAYERCOUNT : i32 = 6;
+//events kept for a player that is not polling, older ones get dropped
+const MAX_EVENTS : usize = 20;
+//how many moves a player can have waiting at once
+const MAX_QUEUE : usize = 10;
+//the flood stops once only the 3x3 around the middle is left
+const MAX_FLOOD_RINGS: i32 = BOARD_SIZE/2 - 1;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 struct Pos{
@@ -34,6 +45,10 @@ struct PosError;
 impl Pos{
 
 
+    fn from_num(n:i32)->Pos{
+        Pos::from_ints(n % BOARD_SIZE, n / BOARD_SIZE)
+    }
+
     fn from_ints(x:i32, y:i32)-> Pos{
         Pos{
             x,
@@ -41,6 +56,11 @@ impl Pos{
             n: (x + y * BOARD_SIZE) as i32,
         }
     }
+    //distance to the closest edge of the board, the outermost tiles are ring 0
+    fn ring(&self)->i32{
+        *[self.x, self.y, BOARD_SIZE - 1 - self.x, BOARD_SIZE - 1 - self.y].iter().min().unwrap()
+    }
+
     fn step(&self,x:i32,y:i32)->Result<Pos,PosError> {
         let new_x = self.x as i32 + x;
         let new_y = self.y as i32 + y;
@@ -74,38 +94,44 @@ pub struct Player{
     value: i32,
     king_pos:Pos,
     energy: f32,
-    got_treasure : bool,
-    last_move_time: time::Instant,
+    banked : i32,
+    //when the piece on each tile may act again
+    cooldowns: HashMap<i32, time::Instant>,
     update_sender : Option<oneshot::Sender<GameMessage>>,
     view_changed : bool,
+    //messages that cant be rebuilt from the board, they wait for the next poll
+    events : VecDeque<GameMessage>,
+    //direction the player's pawns walk in, away from the starting side
+    forward : (i32,i32),
+    //what the player saw on each tile the last time it was in sight
+    memory : HashMap<i32, (Tile, time::Instant)>,
+    //moves that go off on their own once the piece and the energy are ready
+    queue : Vec<QueuedMove>,
+    next_queue_id : i32,
+    //ready to start in the lobby
+    ready : bool,
+    //last time the client asked for an update or moved
+    last_seen : time::Instant,
+    connected : bool,
 }
 
 impl Player{
-    
-    fn relative_to_absolute(&self,num:i32)->Pos{
-
-        let relative_point = ((num)/9,(num)%9);
 
-        let king_pos = self.king_pos;
-
-        Pos::from_ints(
-            relative_point.0+king_pos.x - VIEW_RADIUS , 
-            relative_point.1+king_pos.y - VIEW_RADIUS ,
-        )
-    }
-
-    fn can_see(&self,pos:Pos)->bool{
-
-        let dx = (self.king_pos.x - pos.x).abs();
-        let dy = (self.king_pos.y - pos.y).abs();
-
-        let res = dx <= VIEW_RADIUS && dy <= VIEW_RADIUS;
-        if ! res{
-            println!("cant see that far")
+    //unlike a view an event is kept until the player comes for it
+    fn send_event(&mut self, msg:GameMessage){
+        match self.update_sender.take(){
+            Some(sender)=>{
+                _ = sender.send(msg);
+            }
+            None=>{
+                self.events.push_back(msg);
+                if self.events.len() > MAX_EVENTS{
+                    self.events.pop_front();
+                }
+            }
         }
-        res
     }
-
+    
     fn send_update(&mut self,msg:GameMessage){
         match std::mem::replace(&mut self.update_sender, None){
             Some( sender)=>{
@@ -122,53 +148,56 @@ impl Player{
 
 }
 
-#[derive(Clone, Copy, Debug,PartialEq,Serialize)]
-enum Piece{
-    King,
-    Rook,
-    Knight,
-    Bishop,
-    Queen,
-    Pawn,
+//a premove, waits in the players queue until it can be made
+#[derive(Clone, Copy, Debug)]
+struct QueuedMove{
+    id: i32,
+    start: i32,
+    end: i32,
+    spawn: i32,
+    //extra condition on top of the cost of the move
+    min_energy: f32,
 }
 
-impl Piece{
-    fn from_num(num:i32)->Piece{
-
-        match num{
-            1=>Piece::King,
-            2=>Piece::Queen,
-            3=>Piece::Bishop,
-            4=>Piece::Knight,
-            5=>Piece::Rook,
-            6=>Piece::Pawn,
-            _=>{panic!{"cant convert {} to piece",num}},
-        }
-    }
+//a move that waits for the end of the round in turn based games
+#[derive(Clone, Copy, Debug)]
+struct PlannedMove{
+    number: PlayerNumber,
+    token: PlayerToken,
+    start: i32,
+    end: i32,
+    spawn: i32,
+}
 
-    fn _to_num(&self)->usize{
-        match self{
-            Piece::King=>1,
-            Piece::Queen=>2,
-            Piece::Bishop=>3,
-            Piece::Knight=>4,
-            Piece::Rook=>5,
-            Piece::Pawn=>6,
-        }
-    }
+//what the lobby browser shows about an open game
+#[derive(Clone, Debug, Serialize)]
+pub struct LobbySummary{
+    pub id: i32,
+    pub players: Vec<String>,
+    pub mode: GameMode,
+    pub turn_based: bool,
+    pub team_size: i32,
+    pub board_size: i32,
+    pub treasure_count: usize,
+    //what the pot will hold once the game starts
+    pub stake: i32,
+    pub player_count: i32,
+    pub max_players: i32,
+}
 
-    fn get_cost(&self)->u32{
-        match self{
-            Piece::King=>{panic!("cant take cost of king")},
-            Piece::Queen=>9,
-            Piece::Bishop=>3,
-            Piece::Knight=>3,
-            Piece::Rook=>5,
-            Piece::Pawn=>1,
-        }
-    }
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TreasureState{
+    Lying{ pos: Pos, since: time::Instant },
+    Carried(PlayerNumber),
+    //banked or otherwise gone, comes back home after the respawn time
+    Respawning(time::Instant),
 }
 
+#[derive(Clone, Copy, Debug)]
+struct Treasure{
+    home: Pos,
+    state: TreasureState,
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 pub struct Tile{
@@ -182,13 +211,24 @@ enum TileGround{
     Ground,
     Safe,
     Treasure,
-    // Water,
+    Water,
 }
 
 #[derive(Clone, Copy,Debug,PartialEq,Serialize)]
 enum TileStatus{
     Empty,
     Taken( PlayerNumber,Piece),
+    //left behind by a player that left, blocks the tile for everyone
+    Obstacle,
+    //left behind by a player that left, anyone can take it
+    Abandoned(Piece),
+}
+
+impl TileStatus{
+
+    fn is_empty(&self)->bool{
+        *self == TileStatus::Empty
+    }
 }
 
 pub struct Game{
@@ -198,9 +238,44 @@ pub struct Game{
     board : [Tile; (BOARD_SIZE * BOARD_SIZE) as usize],
     value : i32,
     conclusion : Option<GameMessage>,
-    treasure_holder: Option<PlayerNumber>,
-    treasure_position: Option<i32>,
+    treasures: Vec<Treasure>,
     status : GameStatus,
+    rules : GameRules,
+    //last message for players that are no longer in the game
+    departed : HashMap<PlayerToken, GameMessage>,
+    //scores that still have to be written for players that left during the game
+    settlements : Vec<(String, i32)>,
+    //name and settled score of every player number that lost its king, their team can still win
+    eliminated : HashMap<i32, (String, i32)>,
+    //share of the pot each player number gets once the game is over
+    payouts : HashMap<i32, i32>,
+    start_time : Option<time::Instant>,
+    //number of outer rings that are under water
+    flooded : i32,
+    //team of every player number that took part, in free for all everyone is their own team
+    teams : HashMap<i32, i32>,
+    clock : Arc<dyn Clock>,
+    ticks : u64,
+    //turn based games collect moves for a round and play them all at once
+    round : i32,
+    round_start : Option<time::Instant>,
+    planned : HashMap<PlayerToken, Vec<PlannedMove>>,
+    //players that have no more moves to give this round
+    turn_done : HashSet<PlayerToken>,
+    //private lobbies are only found by their code, the host runs them
+    pub code : Option<String>,
+    host : Option<PlayerToken>,
+    //lobbies the queue filled are only for the players it picked
+    pub queued : bool,
+    //when the countdown to start began, runs while enough players are ready
+    countdown : Option<time::Instant>,
+    created : time::Instant,
+    finished_at : Option<time::Instant>,
+    spectators : Spectators,
+    //names of everyone who was there at the start
+    roster : Vec<String>,
+    //party of each player number that came with one, they play on the same team
+    parties : HashMap<i32, u32>,
 }
 
 #[derive(PartialEq)]
@@ -212,7 +287,8 @@ pub enum GameStatus{
 
 impl Game{
 
-    pub fn new (id:i32)->Game{
+    pub fn new (id:i32, rules: GameRules, clock: Arc<dyn Clock>)->Game{
+        let spectators = Spectators::new(rules.spectator_delay);
         let mut res = Game {
             id,
             players: HashMap::new(),
@@ -220,16 +296,44 @@ impl Game{
             board: [Tile{ground:TileGround::Ground, status:TileStatus::Empty};(BOARD_SIZE * BOARD_SIZE)as usize],
             value: 0,
             conclusion:None,
-            treasure_holder:None,
-            treasure_position : Some(TREASURE_TILE),
+            treasures: vec![],
             status:GameStatus::Lobby,
-
+            rules,
+            departed: HashMap::new(),
+            settlements: vec![],
+            eliminated: HashMap::new(),
+            payouts: HashMap::new(),
+            start_time: None,
+            flooded: 0,
+            teams: HashMap::new(),
+            clock: clock.clone(),
+            ticks: 0,
+            round: 0,
+            round_start: None,
+            planned: HashMap::new(),
+            turn_done: HashSet::new(),
+            code: None,
+            host: None,
+            queued: false,
+            countdown: None,
+            created: clock.now(),
+            finished_at: None,
+            spectators,
+            roster: vec![],
+            parties: HashMap::new(),
         };
         for i in 0..BOARD_SIZE{
             let pos = Pos::from_ints(0,i);
             res.board[pos.n as usize].ground = TileGround::Safe;
         }
-        res.board[TREASURE_TILE as usize].ground = TileGround::Treasure;
+
+        let count = res.rules.treasure_count.clamp(1, TREASURE_SPOTS.len());
+        let now = res.now();
+        for (x,y) in TREASURE_SPOTS.iter().take(count){
+            let home = Pos::from_ints(*x, *y);
+            res.treasures.push(Treasure{ home, state: TreasureState::Lying { pos: home, since: now } });
+            res.board[home.n as usize].ground = TileGround::Treasure;
+        }
         res
     }
 
@@ -243,31 +347,46 @@ impl Game{
 
         let mut rng = rand::thread_rng();
 
-        let num = self.players.len() as i32;
+        //numbers of players that left the lobby get reused
+        let num = (0..MAXPLAYERCOUNT).find(|n| self.players.values().all(|p| p.number.0 != *n)).unwrap_or(self.players.len() as i32);
         let token = rng.gen::<u32>();
 
         let start_position = Pos::from_ints( 2,(num*2+2)%BOARD_SIZE);
 
 
-        self.board[start_position.n as usize].status = TileStatus::Taken(PlayerNumber(num), Piece::King);
+        self.board[start_position.n as usize].status = TileStatus::Taken(PlayerNumber(num), Piece::KING);
 
         println!("adding king on field {}",start_position.n);
 
 
         let new_player = Player { 
             id: id.clone(),
-            number: PlayerNumber(self.players.len() as i32),
+            number: PlayerNumber(num),
             token,
             value: score,
             king_pos:start_position,
             energy: 3.,
-            got_treasure: false,
-            last_move_time: time::Instant::now(),
+            banked: 0,
+            cooldowns: HashMap::new(),
+            queue: vec![],
+            next_queue_id: 0,
+            ready: false,
+            last_seen: self.clock.now(),
+            connected: true,
             update_sender:None,
             view_changed:true,
+            events:VecDeque::new(),
+            forward: forward_from(start_position),
+            memory:HashMap::new(),
         };
 
         self.players.insert(token,new_player);
+
+        if self.code.is_some() && self.host.is_none(){
+            self.host = Some(token);
+        }
+        //a new player is not ready yet
+        self.check_countdown();
         
         // let t  = time::Instant::now();
         let lobby = self.get_lobby_info();
@@ -278,11 +397,22 @@ impl Game{
     }
 
     pub fn remove_player_from_lobby(&mut self,token: &u32)->Result<(),NotFound<String>>{
-        if !self.is_open(){
+        if !self.in_lobby(){
             return Err(NotFound("game allready in progress".to_string()))
         }
         match self.players.remove(token){
-            Some(_)=>{
+            Some(player)=>{
+                self.board[player.king_pos.n as usize].status = TileStatus::Empty;
+                //the number goes to the next one to join, the party does not
+                self.parties.remove(&player.number.0);
+                if self.host == Some(*token){
+                    self.host = self.players.keys().next().cloned();
+                }
+                //whoever is left has to wait the full countdown again
+                self.countdown = None;
+                self.check_countdown();
+                let lobby = self.get_lobby_info();
+                self.broadcast(lobby);
                 return Ok(())
             }
             None=>{
@@ -291,6 +421,21 @@ impl Game{
         }
     }
 
+    //the host of a private lobby throws someone out
+    pub fn kick(&mut self, host: PlayerToken, number: i32)->Result<(),String>{
+        if self.host != Some(host){
+            return Err("only the host can kick players".to_string())
+        }
+        let token = match self.players.values().find(|p| p.number.0 == number){
+            Some(player) if player.token != host=>player.token,
+            Some(_)=>return Err("the host cant kick themselves".to_string()),
+            None=>return Err("cant find that player".to_string()),
+        };
+        self.remove_player_from_lobby(&token).map_err(|e| e.0)?;
+        self.departed.insert(token, GameMessage::Kicked { game_id: self.id });
+        Ok(())
+    }
+
     pub fn remove_player_from_game(&mut self, token:&u32)->Result<GameMessage,NotFound<String>>{
         if self.is_open(){
             return Err(NotFound("game not started".to_string()))
@@ -300,32 +445,238 @@ impl Game{
             return Err(NotFound("cant get player".to_string()));
         }
 
-        let gameover = self.players.len() == 2;
+        let leaver = self.players.get(token).unwrap().number;
+        let remaining : Vec<PlayerNumber> = self.players.values().map(|p| p.number).filter(|n| *n != leaver).collect();
 
-        if gameover{
-            //make other player win
-            let mut tokens = self.players.keys();
-            let mut winner_token = *tokens.next().unwrap();
-            if winner_token == *token{
-                winner_token = *tokens.next().unwrap();
-            }
-            let winner = self.players.get(&winner_token).unwrap();
-            let msg = GameMessage::End { winning_number: winner.number.0, value: self.value };
-            let res = Ok(GameMessage::End { winning_number: winner.number.0, value: self.value });
-            self.broadcast(msg);
-            self.status = GameStatus::Finished;
-            return res;
+        if self.team_count(&remaining) == 1{
+            //make the other team win
+            return Ok(self.win(remaining[0]));
         }else{
-            let player = self.players.get(&token).unwrap();
+            let player = self.players.remove(&token).unwrap();
             let fee = player.value/10;
             let new_val = player.value - fee;
-            let res = Ok (GameMessage::Leave { name: player.id.0.clone(), value:new_val });
-            self.players.remove(&token);
-            return res;
+            self.abandon_pieces(player.number, player.king_pos);
+
+            let tokens : Vec<PlayerToken> = self.players.keys().cloned().collect();
+            for tok in tokens{
+                self.update_player_view(&tok);
+            }
+            return Ok (GameMessage::Leave { name: player.id.0.clone(), value:new_val });
+        }
+
+    }
+
+    //a player lost the king, the fee is paid and the pieces are handled like the player left
+    fn eliminate_player(&mut self, number: PlayerNumber)->MoveResult{
+
+        let token = match self.players.values().find(|p| p.number == number){
+            Some(p)=>p.token,
+            None=>return MoveResult::Success,
+        };
+
+        let player = self.players.remove(&token).unwrap();
+        let fee = player.value/10;
+        let new_val = player.value - fee;
+        println!("player {} eliminated",player.id.0);
+
+        self.abandon_pieces(player.number, player.king_pos);
+        self.settlements.push((player.id.0.clone(), new_val));
+        self.eliminated.insert(number.0, (player.id.0.clone(), new_val));
+        self.departed.insert(token, GameMessage::Leave { name: player.id.0.clone(), value: new_val });
+
+        self.broadcast_event(GameMessage::Eliminated { name: player.id.0.clone(), number: number.0 });
+
+        let last_king_wins = self.rules.last_king_standing || self.rules.mode == GameMode::ShrinkingIsland;
+        let remaining : Vec<PlayerNumber> = self.players.values().map(|p| p.number).collect();
+        if last_king_wins && self.team_count(&remaining) == 1{
+            let winner = remaining[0];
+            self.win(winner);
+            return MoveResult::End { winner: winner.0 }
         }
 
+        let tokens : Vec<PlayerToken> = self.players.keys().cloned().collect();
+        for tok in tokens{
+            self.update_player_view(&tok);
+        }
+        MoveResult::Success
     }
 
+    pub fn take_settlements(&mut self)->Vec<(String,i32)>{
+        std::mem::take(&mut self.settlements)
+    }
+
+    //clear the board of a player that is gone, the king always goes and takes the treasure down with it
+    fn abandon_pieces(&mut self, number: PlayerNumber, king_pos: Pos){
+
+        let rule = self.rules.abandon;
+
+        for tile in self.board.iter_mut(){
+            if let TileStatus::Taken(pn, piece) = tile.status{
+                if pn != number{
+                    continue
+                }
+                tile.status = match (piece, rule){
+                    (Piece::KING, _)=>TileStatus::Empty,
+                    (_, AbandonRule::Remove)=>TileStatus::Empty,
+                    (_, AbandonRule::Obstacle)=>TileStatus::Obstacle,
+                    (_, AbandonRule::Capturable)=>TileStatus::Abandoned(piece),
+                };
+            }
+        }
+
+        self.drop_treasures(number, king_pos);
+    }
+
+    fn carried_treasures(&self, number: PlayerNumber)->i32{
+        self.treasures.iter().filter(|t| t.state == TreasureState::Carried(number)).count() as i32
+    }
+
+    //everything the player carries falls on the given tile
+    fn drop_treasures(&mut self, number: PlayerNumber, pos: Pos){
+        let now = self.now();
+        for treasure in self.treasures.iter_mut(){
+            if treasure.state == TreasureState::Carried(number){
+                println!("dropping treasure on {}",pos.n);
+                treasure.state = TreasureState::Lying { pos, since: now };
+            }
+        }
+        self.mark_treasure_ground(pos);
+    }
+
+    //plain ground shows whether a treasure lies on it, harbours and water keep what they are
+    fn mark_treasure_ground(&mut self, pos: Pos){
+        let lying = self.treasures.iter().any(|t| matches!(t.state, TreasureState::Lying { pos: p, .. } if p == pos));
+        let tile = &mut self.board[pos.n as usize];
+        match (tile.ground, lying){
+            (TileGround::Ground, true)=>tile.ground = TileGround::Treasure,
+            (TileGround::Treasure, false)=>tile.ground = TileGround::Ground,
+            _=>{}
+        }
+    }
+
+    //a piece of the player reached this tile and picks up whatever lies there
+    fn collect_treasures(&mut self, number: PlayerNumber, pos: Pos){
+        for treasure in self.treasures.iter_mut(){
+            if let TreasureState::Lying { pos: lying, .. } = treasure.state{
+                if lying == pos{
+                    treasure.state = TreasureState::Carried(number);
+                }
+            }
+        }
+        self.mark_treasure_ground(pos);
+    }
+
+    //the king reached the harbour, carried treasures count for the player and go back into the game later
+    fn bank_treasures(&mut self, token: PlayerToken)->Option<MoveResult>{
+
+        let number = self.players.get(&token)?.number;
+        let now = self.now();
+        let mut count = 0;
+
+        for treasure in self.treasures.iter_mut(){
+            if treasure.state == TreasureState::Carried(number){
+                treasure.state = TreasureState::Respawning(now);
+                count += 1;
+            }
+        }
+        if count == 0{
+            return None
+        }
+
+        let player = self.players.get_mut(&token)?;
+        player.banked += count;
+        println!("player {} banked {} treasures",player.id.0,player.banked);
+
+        if player.banked >= self.rules.treasures_to_win{
+            self.win(number);
+            return Some(MoveResult::End{winner:number.0})
+        }
+        None
+    }
+
+    //lost treasures return to their home tile once nobody picked them up for a while
+    fn respawn_treasures(&mut self){
+
+        let respawn = self.rules.treasure_respawn;
+        let mut moved = vec![];
+
+        for i in 0..self.treasures.len(){
+            let treasure = self.treasures[i];
+            let due = match treasure.state{
+                TreasureState::Lying { pos, since }=> pos != treasure.home && self.since(since) >= respawn,
+                TreasureState::Respawning(since)=> self.since(since) >= respawn,
+                TreasureState::Carried(_)=>false,
+            };
+            let home = self.board[treasure.home.n as usize];
+            if !due || !home.status.is_empty() || home.ground == TileGround::Water{
+                continue
+            }
+
+            println!("treasure respawns at {}",treasure.home.n);
+            self.treasures[i].state = TreasureState::Lying { pos: treasure.home, since: self.now() };
+
+            if let TreasureState::Lying { pos, .. } = treasure.state{
+                self.mark_treasure_ground(pos);
+                moved.push(pos);
+            }
+            self.mark_treasure_ground(treasure.home);
+            moved.push(treasure.home);
+        }
+
+        for pos in moved{
+            self.update_views(pos, pos);
+        }
+    }
+
+    //positions of all treasures once sudden death reveals them, carried ones show up on the carriers king
+    fn revealed_treasures(&self)->Vec<(i32,i32)>{
+        let reveal = self.rules.sudden_death == SuddenDeath::TreasureReveal && matches!(self.clock(), Some((_, true)));
+        if !reveal{
+            return vec![]
+        }
+        let mut res = vec![];
+        for treasure in self.treasures.iter(){
+            match treasure.state{
+                TreasureState::Lying { pos, .. }=>res.push((pos.x, pos.y)),
+                TreasureState::Carried(num)=>{
+                    if let Some(p) = self.players.values().find(|p| p.number == num){
+                        res.push((p.king_pos.x, p.king_pos.y))
+                    }
+                }
+                TreasureState::Respawning(_)=>{}
+            }
+        }
+        res
+    }
+
+    //put down carried treasures on a free tile next to the king
+    pub fn drop_treasure(&mut self, token: PlayerToken, target: i32)->MoveResult{
+
+        if !self.rules.voluntary_drop || self.status != GameStatus::Running || target < 0 || target >= LAST_TILE{
+            return MoveResult::Fail
+        }
+
+        let (number, king_pos, pos) = match self.players.get(&token){
+            Some(player)=>(player.number, player.king_pos, Pos::from_num(target)),
+            None=>return MoveResult::Fail,
+        };
+
+        let adjacent = [STRAIGHTS,DIAGONALS].concat().iter().any(|dir| king_pos.step(dir.0, dir.1).map(|p| p == pos).unwrap_or(false));
+        if !adjacent || self.carried_treasures(number) == 0{
+            return MoveResult::Fail
+        }
+
+        let tile = self.board[pos.n as usize];
+        if tile.ground != TileGround::Ground || !tile.status.is_empty(){
+            return MoveResult::Fail
+        }
+
+        self.drop_treasures(number, pos);
+        self.update_views(king_pos, pos);
+        MoveResult::Success
+    }
+
+    //the host of a private lobby starts right away, everyone else only says they are ready
     pub fn start(&mut self, token: u32)->Result<(),String>{
 
         if ! self.players.contains_key(&token){
@@ -333,6 +684,14 @@ impl Game{
             return Err("cant find player token to start game".to_string())
         }
 
+        if self.host.is_none(){
+            return self.set_ready(token, true)
+        }
+
+        if self.host != Some(token){
+            return Err("only the host can start this game".to_string())
+        }
+
         if (self.players.len() as i32) < MINPLAYERCOUNT{
             println!("not enough players to start");
             return Err("not enough players to start game".to_string())
@@ -342,68 +701,982 @@ impl Game{
             return Ok(())
         }
 
+        self.begin();
+        Ok(())
+    }
+
+    pub fn set_ready(&mut self, token: PlayerToken, ready: bool)->Result<(),String>{
+
+        if self.status != GameStatus::Lobby{
+            return Ok(())
+        }
+
+        match self.players.get_mut(&token){
+            Some(player)=>player.ready = ready,
+            None=>return Err("cant find player token to get ready".to_string()),
+        }
+
+        if !ready{
+            self.countdown = None;
+        }
+        self.check_countdown();
+
+        let lobby = self.get_lobby_info();
+        self.broadcast(lobby);
+        Ok(())
+    }
+
+    //the countdown runs while at least the minimum of players is there and all of them are ready
+    fn check_countdown(&mut self){
+
+        let enough = (self.players.len() as i32) >= MINPLAYERCOUNT && self.players.values().all(|p| p.ready);
+
+        match (enough, self.countdown){
+            (true, None)=>self.countdown = Some(self.now()),
+            (false, Some(_))=>self.countdown = None,
+            _=>{}
+        }
+    }
+
+    //seconds until the lobby starts on its own, -1 if no countdown is running
+    fn countdown_left(&self)->f32{
+        match self.countdown{
+            Some(since)=>f32::max(self.rules.ready_countdown - self.since(since), 0.),
+            None=>-1.,
+        }
+    }
+
+    //the lobby starts by itself once it is full or the countdown ran out
+    fn tick_lobby(&mut self){
+
+        let full = self.players.len() as i32 >= MAXPLAYERCOUNT;
+        let counted_down = self.countdown.is_some() && self.countdown_left() <= 0.;
+
+        if full || counted_down{
+            println!("lobby {} starts by itself",self.id);
+            self.begin();
+        }
+    }
+
+    fn begin(&mut self){
+
         self.status = GameStatus::Running;
+        self.countdown = None;
+        self.assign_teams();
+        self.roster = self.players.values().map(|p| p.id.0.clone()).collect();
 
         let mut tokens : Vec<_> = vec![];
 
-        let start_time = time::Instant::now();
+        self.start_time = Some(self.now());
+        self.round_start = self.start_time;
 
         for player in self.players.values_mut(){
             tokens.push(player.token);
             self.value += player.value/10;
-            player.last_move_time = start_time;
             
         }
-        for tok in tokens{
-            self.update_player_view(&tok);
+        for tok in tokens{
+            self.update_player_view(&tok);
+        }
+    }
+
+    pub async fn end(&mut self, secret:&String){
+
+        for (_,p) in self.players.iter_mut(){
+
+
+            let fee = p.value/10;
+            let payout = self.payouts.get(&p.number.0).cloned().unwrap_or(0);
+            let target_value = p.value + payout - fee;
+
+
+            let fut = database::set_player_score(&p.id.0,target_value,secret);
+            if let Err(e) = fut.await{
+                println!("error setting scores {}",e.0);
+            }
+        }
+
+        //whatever came in since the last request, like the share of a teammate who went out before
+        for (name, value) in self.take_settlements(){
+            if let Err(e) = database::set_player_score(&name, value, secret).await{
+                println!("error setting scores {}",e.0);
+            }
+        }
+    }
+
+    fn allied(&self, a: PlayerNumber, b: PlayerNumber)->bool{
+        if a == b{
+            return true
+        }
+        match (self.teams.get(&a.0), self.teams.get(&b.0)){
+            (Some(ta), Some(tb))=>ta == tb,
+            _=>false,
+        }
+    }
+
+    fn team_count(&self, numbers: &[PlayerNumber])->usize{
+        let mut teams : Vec<i32> = numbers.iter().map(|n| *self.teams.get(&n.0).unwrap_or(&n.0)).collect();
+        teams.sort();
+        teams.dedup();
+        teams.len()
+    }
+
+    //can a piece of this player end its move here
+    fn can_enter(&self, pos: Pos, number: PlayerNumber)->bool{
+        match self.board[pos.n as usize].status{
+            TileStatus::Empty=>true,
+            TileStatus::Taken(pn,_)=> !self.allied(pn, number),
+            TileStatus::Abandoned(_)=>true,
+            TileStatus::Obstacle=>false,
+        }
+    }
+
+    //the player came with this party
+    pub fn set_party(&mut self, name: &str, party: u32){
+        if let Some(player) = self.players.values().find(|p| p.id.0 == name){
+            self.parties.insert(player.number.0, party);
+        }
+    }
+
+    //split players into at least two teams of about the configured size, parties are kept together where they fit
+    fn assign_teams(&mut self){
+        let mut numbers : Vec<i32> = self.players.values().map(|p| p.number.0).collect();
+        numbers.sort();
+
+        let team_size = self.rules.team_size.max(1) as usize;
+        if team_size == 1{
+            for num in numbers{
+                self.teams.insert(num, num);
+            }
+            return
+        }
+        //never fewer than two teams, or nobody would have anyone to fight
+        let team_count = ((numbers.len() + team_size - 1) / team_size).max(2);
+        let capacity = (numbers.len() + team_count - 1) / team_count;
+
+        //parties go first, biggest first, so they still fit into one team
+        let mut units : Vec<Vec<i32>> = vec![];
+        let mut party_units : HashMap<u32, usize> = HashMap::new();
+        for num in numbers{
+            match self.parties.get(&num){
+                Some(party) if party_units.contains_key(party)=>units[party_units[party]].push(num),
+                Some(party)=>{
+                    party_units.insert(*party, units.len());
+                    units.push(vec![num]);
+                }
+                None=>units.push(vec![num]),
+            }
+        }
+        units.sort_by_key(|unit| std::cmp::Reverse(unit.len()));
+
+        //every unit goes to the emptiest team, what does not fit spills over into the next emptiest
+        let mut sizes = vec![0; team_count];
+        for unit in units{
+            let mut team = (0..team_count).min_by_key(|t| sizes[*t]).unwrap_or(0);
+            for num in unit{
+                if sizes[team] >= capacity{
+                    team = (0..team_count).min_by_key(|t| sizes[*t]).unwrap_or(0);
+                }
+                sizes[team] += 1;
+                self.teams.insert(num, team as i32);
+            }
+        }
+    }
+
+    //the whole team of the winner splits the pot, teammates who lost their king too, those who left gave up their share
+    fn win(&mut self, winner: PlayerNumber)->GameMessage{
+
+        let mut winners : Vec<i32> = self.teams.keys()
+            .map(|n| PlayerNumber(*n))
+            .filter(|n| self.allied(*n, winner))
+            .filter(|n| self.players.values().any(|p| p.number == *n) || self.eliminated.contains_key(&n.0))
+            .map(|n| n.0)
+            .collect();
+        if !winners.contains(&winner.0){
+            winners.push(winner.0);
+        }
+        winners.sort();
+
+        let share = self.value / winners.len().max(1) as i32;
+        for num in winners.iter(){
+            match self.eliminated.get(num){
+                //already settled when they went out, the share comes on top
+                Some((name, settled))=>self.settlements.push((name.clone(), settled + share)),
+                None=>{
+                    self.payouts.insert(*num, share);
+                }
+            }
+        }
+        let end_message = GameMessage::End { winning_number: winner.0, value: self.value, winners };
+        self.finish(end_message.clone());
+        end_message
+    }
+
+    //nobody won in time, the pot is shared by the material each player still has on the board
+    fn split_pot(&mut self)->GameMessage{
+
+        let mut material : HashMap<i32,i32> = HashMap::new();
+        for player in self.players.values(){
+            material.insert(player.number.0, 0);
+        }
+        for tile in self.board.iter(){
+            if let TileStatus::Taken(pn, piece) = tile.status{
+                if piece != Piece::KING{
+                    material.entry(pn.0).and_modify(|m| *m += piece.get_cost() as i32);
+                }
+            }
+        }
+
+        let total : i32 = material.values().sum();
+        let mut shares = vec![];
+        for (num, m) in material.iter(){
+            let share = if total == 0{
+                self.value / material.len() as i32
+            }else{
+                self.value * m / total
+            };
+            self.payouts.insert(*num, share);
+            shares.push((*num, share));
+        }
+
+        let end_message = GameMessage::Split { shares };
+        self.finish(end_message.clone());
+        end_message
+    }
+
+    fn finish(&mut self, conclusion: GameMessage){
+        self.broadcast(conclusion.clone());
+        self.spectators.broadcast(conclusion.clone());
+        self.conclusion = Some(conclusion);
+        self.status = GameStatus::Finished;
+        self.finished_at = Some(self.now());
+    }
+
+    //nobody is playing anymore, everyone still in gets their stake back and the stakes of those who left are shared
+    pub fn abandon(&mut self){
+
+        let stakes : Vec<(i32,i32)> = self.players.values().map(|p| (p.number.0, p.value/10)).collect();
+        let own : i32 = stakes.iter().map(|(_, stake)| stake).sum();
+        let rest = (self.value - own).max(0) / stakes.len().max(1) as i32;
+
+        let mut shares = vec![];
+        for (num, stake) in stakes{
+            self.payouts.insert(num, stake + rest);
+            shares.push((num, stake + rest));
+        }
+        println!("game {} was abandoned",self.id);
+        self.finish(GameMessage::Abandoned { game_id: self.id, shares });
+    }
+
+    //tell the players of a lobby that is about to be dropped
+    pub fn close_lobby(&mut self){
+        let msg = GameMessage::Abandoned { game_id: self.id, shares: vec![] };
+        self.broadcast(msg.clone());
+        self.conclusion = Some(msg);
+    }
+
+    //everything spectators might be shown of the board as it is now
+    fn snapshot(&self, now: time::Instant)->Snapshot{
+        let tiles = (0..LAST_TILE).map(|n| (n, self.board[n as usize])).collect();
+
+        let mut sight = HashMap::new();
+        let mut players = vec![];
+        for player in self.players.values(){
+            let vision = self.vision(player.number);
+            sight.insert(player.number.0, (0..LAST_TILE).filter(|n| vision[*n as usize]).collect());
+            players.push((player.id.0.clone(), player.number.0));
+        }
+        players.sort_by_key(|(_, num)| *num);
+
+        Snapshot { at: now, tiles, sight, players }
+    }
+
+    //watch the game from the outside, following one player or the whole board with -1
+    pub fn spectate(&mut self, follow: i32)->Result<GameMessage,String>{
+        if !self.rules.spectators{
+            return Err("this game cant be watched".to_string())
+        }
+        if self.status == GameStatus::Finished{
+            return Err("this game is over".to_string())
+        }
+        let now = self.now();
+        let token = self.spectators.add(follow, now).ok_or("too many spectators".to_string())?;
+
+        let count = self.spectators.count();
+        self.spectators_changed();
+        println!("game {} has {} spectators",self.id,count);
+        Ok(GameMessage::Watching { game_id: self.id, token, delay: self.rules.spectator_delay })
+    }
+
+    pub fn stop_spectating(&mut self, token: u32)->bool{
+        let gone = self.spectators.remove(token);
+        if gone{
+            self.spectators_changed();
+        }
+        gone
+    }
+
+    pub fn follow(&mut self, token: u32, follow: i32)->bool{
+        self.spectators.follow(token, follow)
+    }
+
+    pub fn spectator_update(&mut self, token: u32)->Result<Option<GameMessage>,oneshot::Receiver<GameMessage>>{
+        if self.status == GameStatus::Finished{
+            return Ok(self.conclusion.clone())
+        }
+        let now = self.now();
+        self.spectators.request(token, self.id, now)
+    }
+
+    //players see how many are watching
+    fn spectators_changed(&mut self){
+        if self.status != GameStatus::Running{
+            return
+        }
+        let tokens : Vec<PlayerToken> = self.players.keys().cloned().collect();
+        for tok in tokens{
+            self.update_player_view(&tok);
+        }
+    }
+
+    //the client of this player is still there
+    fn seen(&mut self, token: PlayerToken){
+        let now = self.now();
+        let back = match self.players.get_mut(&token){
+            Some(player)=>{
+                player.last_seen = now;
+                !std::mem::replace(&mut player.connected, true)
+            }
+            None=>false,
+        };
+        if back{
+            println!("player {} is back in game {}",token,self.id);
+            self.connections_changed();
+        }
+    }
+
+    //mark players whose client went quiet
+    fn check_connections(&mut self){
+        let now = self.now();
+        let timeout = self.rules.disconnect_timeout;
+        let mut gone = vec![];
+        for player in self.players.values_mut(){
+            let quiet = now.saturating_duration_since(player.last_seen).as_secs_f32() > timeout;
+            if player.connected && quiet{
+                println!("player {} disconnected from game {}",player.token,self.id);
+                player.connected = false;
+                gone.push((player.number, player.king_pos));
+            }
+        }
+        if gone.is_empty(){
+            return
+        }
+        //whatever they carried is left where their king stands for the others to take
+        if self.status == GameStatus::Running{
+            for (number, king_pos) in gone{
+                self.drop_treasures(number, king_pos);
+            }
+        }
+        self.connections_changed();
+    }
+
+    fn connections_changed(&mut self){
+        if self.status != GameStatus::Running{
+            return
+        }
+        let tokens : Vec<PlayerToken> = self.players.keys().cloned().collect();
+        for tok in tokens{
+            self.update_player_view(&tok);
+        }
+    }
+
+    //a player lost their token, they get a new one and the old one stops working
+    pub fn resume(&mut self, name: &str)->Option<GameMessage>{
+
+        if self.status == GameStatus::Finished{
+            return None
+        }
+
+        let old = self.players.values().find(|p| p.id.0 == name)?.token;
+        let mut player = self.players.remove(&old)?;
+
+        let mut rng = rand::thread_rng();
+        let mut token = rng.gen::<u32>();
+        while self.players.contains_key(&token){
+            token = rng.gen::<u32>();
+        }
+
+        player.token = token;
+        player.update_sender = None;
+        player.view_changed = true;
+        let number = player.number.0;
+        self.players.insert(token, player);
+
+        if let Some(planned) = self.planned.remove(&old){
+            self.planned.insert(token, planned.into_iter().map(|m| PlannedMove { token, ..m }).collect());
+        }
+        if self.turn_done.remove(&old){
+            self.turn_done.insert(token);
+        }
+        if self.host == Some(old){
+            self.host = Some(token);
+        }
+        self.seen(token);
+
+        Some(GameMessage::Join { game_id: self.id, number, token })
+    }
+
+    //seconds since any player showed signs of life, a player waiting on an update counts as there
+    pub fn idle_for(&self)->f32{
+        let waiting = self.players.values().any(|p| p.update_sender.as_ref().map_or(false, |s| !s.is_closed()));
+        if waiting{
+            return 0.
+        }
+        let last = self.players.values().map(|p| p.last_seen).max().unwrap_or(self.created);
+        self.since(last.max(self.created))
+    }
+
+    pub fn is_running(&self)->bool{
+        self.status == GameStatus::Running
+    }
+
+    //how the game ended, for players that were not polling right then
+    pub fn conclusion(&self)->Option<&GameMessage>{
+        self.conclusion.as_ref()
+    }
+
+    pub fn tokens(&self)->Vec<PlayerToken>{
+        self.players.keys().cloned().collect()
+    }
+
+    pub fn roster(&self)->&[String]{
+        &self.roster
+    }
+
+    pub fn rules(&self)->&GameRules{
+        &self.rules
+    }
+
+    pub fn is_finished(&self)->bool{
+        self.status == GameStatus::Finished
+    }
+
+    pub fn has_player(&self, name: &str)->bool{
+        self.players.values().any(|p| p.id.0 == name)
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.players.is_empty()
+    }
+
+    //seconds since the game ended, None while it is still going
+    pub fn finished_for(&self)->Option<f32>{
+        self.finished_at.map(|at| self.since(at))
+    }
+
+    //seconds left on the match clock and whether the game is in sudden death, None if there is no limit
+    fn clock(&self)->Option<(f32, bool)>{
+        let start = self.start_time?;
+        if self.rules.match_duration <= 0.{
+            return None
+        }
+        let elapsed = self.since(start);
+        if elapsed < self.rules.match_duration{
+            Some((self.rules.match_duration - elapsed, false))
+        }else{
+            Some((f32::max(self.rules.match_duration + self.rules.sudden_death_duration - elapsed, 0.), true))
+        }
+    }
+
+    //how many rings should be under water by now and the seconds until the next one goes
+    fn flood_schedule(&self)->Option<(i32, f32)>{
+        if self.rules.mode != GameMode::ShrinkingIsland{
+            return None
+        }
+        let elapsed = self.since(self.start_time?);
+        let first = self.rules.flood_start;
+        let interval = f32::max(self.rules.flood_interval, 1.);
+
+        let due = if elapsed < first{
+            0
+        }else{
+            i32::min(1 + ((elapsed - first) / interval) as i32, MAX_FLOOD_RINGS)
+        };
+        let next_in = if due >= MAX_FLOOD_RINGS{
+            -1.
+        }else{
+            first + due as f32 * interval - elapsed
+        };
+        Some((due, next_in))
+    }
+
+    //put the rings that are due under water, returns true if that ended the game
+    fn flood(&mut self)->bool{
+
+        let due = match self.flood_schedule(){
+            Some((due,_))=>due,
+            None=>return false,
+        };
+        if self.flooded >= due{
+            return false
+        }
+
+        let mut drowned = vec![];
+        let now = self.now();
+        while self.flooded < due{
+            let ring = self.flooded;
+            println!("flooding ring {} in game {}",ring,self.id);
+
+            for n in 0..LAST_TILE{
+                let pos = Pos::from_num(n);
+                if pos.ring() != ring{
+                    continue
+                }
+                let tile = &mut self.board[n as usize];
+                match tile.status{
+                    TileStatus::Taken(_, piece) if piece.def().terrain == Terrain::Water=>{}
+                    TileStatus::Taken(pn, Piece::KING)=>{
+                        drowned.push(pn);
+                        tile.status = TileStatus::Empty;
+                    }
+                    _=>{
+                        tile.status = TileStatus::Empty;
+                    }
+                }
+                tile.ground = TileGround::Water;
+
+                for treasure in self.treasures.iter_mut(){
+                    if matches!(treasure.state, TreasureState::Lying { pos: p, .. } if p == pos){
+                        treasure.state = TreasureState::Respawning(now);
+                    }
+                }
+            }
+            self.flooded += 1;
+        }
+
+        for number in drowned{
+            //whatever the king carried sinks with it
+            for treasure in self.treasures.iter_mut(){
+                if treasure.state == TreasureState::Carried(number){
+                    treasure.state = TreasureState::Respawning(now);
+                }
+            }
+            if let MoveResult::End { winner: _ } = self.eliminate_player(number){
+                return true
+            }
+        }
+
+        let tokens : Vec<PlayerToken> = self.players.keys().cloned().collect();
+        for tok in tokens{
+            self.update_player_view(&tok);
+        }
+        false
+    }
+
+    //energy regen per second, drains away over sudden death if the rules say so
+    fn energy_regen(&self)->f32{
+        match (self.clock(), self.rules.sudden_death){
+            (Some((left, true)), SuddenDeath::ShrinkingRegen) if self.rules.sudden_death_duration > 0. =>{
+                ENERGY_REGEN * left / self.rules.sudden_death_duration
+            }
+            (Some((_, true)), SuddenDeath::ShrinkingRegen)=>0.,
+            _=>ENERGY_REGEN,
+        }
+    }
+
+    fn now(&self)->time::Instant{
+        self.clock.now()
+    }
+
+    fn since(&self, then: time::Instant)->f32{
+        self.now().saturating_duration_since(then).as_secs_f32()
+    }
+
+    //advance a running game by one tick of dt seconds, returns true if the game just ended
+    pub fn tick(&mut self, dt: f32)->bool{
+
+        if self.status == GameStatus::Lobby{
+            self.tick_lobby();
+            return false
+        }
+
+        if self.status != GameStatus::Running{
+            return false
+        }
+
+        self.ticks += 1;
+
+        self.check_connections();
+
+        let regen = self.energy_regen();
+        let pause = self.rules.disconnect == DisconnectRule::PauseEnergy;
+        for player in self.players.values_mut(){
+            if pause && !player.connected{
+                continue
+            }
+            player.energy = f32::min(player.energy + regen * dt, MAX_ENERGY);
+        }
+
+        if self.ticks % STATE_TICKS == 0{
+            //energy and clocks changed for everyone
+            let tokens : Vec<PlayerToken> = self.players.keys().cloned().collect();
+            for tok in tokens{
+                self.update_player_view(&tok);
+            }
+
+            let now = self.now();
+            let snapshot = self.snapshot(now);
+            self.spectators.record(snapshot, self.id, now);
+        }
+
+        self.respawn_treasures();
+
+        if self.run_queues(){
+            return true
+        }
+
+        if self.rules.turn_based && self.round_left() <= 0.{
+            if let MoveResult::End { winner: _ } = self.resolve_round(){
+                return true
+            }
+        }
+
+        if self.rules.mode == GameMode::ShrinkingIsland && self.flood(){
+            return true
+        }
+
+        let (left, sudden) = match self.clock(){
+            Some(c)=>c,
+            None=>return false,
+        };
+
+        if sudden && (self.rules.sudden_death == SuddenDeath::SplitPot || left <= 0.){
+            println!("time is up in game {}",self.id);
+            self.split_pot();
+            return true
+        }
+        false
+    }
+
+    pub fn get_lobby_info(&self)-> GameMessage{
+
+        let mut player_list = vec![];
+
+        for player in self.players.values(){
+            player_list.push((player.id.0.clone(),player.number.0))
+        }
+
+        let host = self.host.and_then(|tok| self.players.get(&tok)).map(|p| p.number.0).unwrap_or(-1);
+
+        let mut ready : Vec<i32> = self.players.values().filter(|p| p.ready).map(|p| p.number.0).collect();
+        ready.sort();
+
+        GameMessage::Lobby { players: player_list, host, code: self.code.clone(), ready, countdown: self.countdown_left() }
+        
+    }
+
+    pub fn is_open(&self)->bool{
+        self.in_lobby() && (self.players.len() as i32) < MAXPLAYERCOUNT
+    }
+
+    pub fn summary(&self)->LobbySummary{
+        let mut players : Vec<&Player> = self.players.values().collect();
+        players.sort_by_key(|p| p.number.0);
+
+        LobbySummary {
+            id: self.id,
+            players: players.iter().map(|p| p.id.0.clone()).collect(),
+            mode: self.rules.mode,
+            turn_based: self.rules.turn_based,
+            team_size: self.rules.team_size,
+            board_size: BOARD_SIZE,
+            treasure_count: self.treasures.len(),
+            stake: players.iter().map(|p| p.value/10).sum(),
+            player_count: players.len() as i32,
+            max_players: MAXPLAYERCOUNT,
+        }
+    }
+
+    //still waiting to start, full or not
+    pub fn in_lobby(&self)->bool{
+        self.status == GameStatus::Lobby
+    }
+
+    pub fn make_move(&mut self,token: u32,start:i32,end:i32,spawn:i32)->MoveResult{   
+
+        self.seen(token);
+
+        if self.rules.turn_based{
+            return self.plan_move(token, start, end, spawn)
+        }
+        self.execute_move(token, start, end, spawn)
+    }
+
+    //seconds left to hand in moves for this round
+    fn round_left(&self)->f32{
+        match self.round_start{
+            Some(start)=>self.rules.round_duration - self.since(start),
+            None=>-1.,
+        }
+    }
+
+    //put a move at the back of the players queue, returns its id
+    pub fn queue_move(&mut self, token: PlayerToken, start:i32, end:i32, spawn:i32, min_energy:f32)->Option<i32>{
+
+        if self.rules.turn_based || self.status != GameStatus::Running{
+            return None
+        }
+        if start == end || start <0 || end < 0 || start >= LAST_TILE || end >= LAST_TILE{
+            return None
+        }
+        let player = self.players.get_mut(&token)?;
+        if player.queue.len() >= MAX_QUEUE{
+            return None
+        }
+        let id = player.next_queue_id;
+        player.next_queue_id += 1;
+        player.queue.push(QueuedMove { id, start, end, spawn, min_energy });
+        player.view_changed = true;
+        Some(id)
+    }
+
+    pub fn cancel_queued(&mut self, token: PlayerToken, id: i32)->MoveResult{
+        match self.players.get_mut(&token){
+            Some(player)=>{
+                let before = player.queue.len();
+                player.queue.retain(|m| m.id != id);
+                if player.queue.len() == before{
+                    return MoveResult::Fail
+                }
+                player.view_changed = true;
+                MoveResult::Success
+            }
+            None=>MoveResult::Fail,
+        }
+    }
+
+    //move a queued move to a new place in the queue, 0 is next
+    pub fn reorder_queued(&mut self, token: PlayerToken, id: i32, index: usize)->MoveResult{
+        match self.players.get_mut(&token){
+            Some(player)=>{
+                let at = match player.queue.iter().position(|m| m.id == id){
+                    Some(at)=>at,
+                    None=>return MoveResult::Fail,
+                };
+                let queued = player.queue.remove(at);
+                let index = index.min(player.queue.len());
+                player.queue.insert(index, queued);
+                player.view_changed = true;
+                MoveResult::Success
+            }
+            None=>MoveResult::Fail,
+        }
+    }
+
+    //energy a move needs before it is worth trying
+    fn move_cost(&self, token: PlayerToken, start: i32, end: i32, spawn: i32)->f32{
+        if spawn == 0{
+            return 1.
+        }
+        match self.board[start as usize].status{
+            TileStatus::Taken(_, Piece::KING)=>{
+                Piece::from_num(spawn).map(|p| f32::max(p.get_cost() as f32, 1.)).unwrap_or(1.)
+            }
+            TileStatus::Taken(_, Piece::PAWN)=>{
+                1. + self.promotion_cost(Pos::from_num(end), token, spawn).map(|(_, cost)| cost).unwrap_or(0.)
+            }
+            _=>1.,
         }
-        Ok(())
     }
 
-    pub async fn end(&mut self, secret:&String, winner: i32){
+    //try the first queued move of every player, returns true if one of them ended the game
+    fn run_queues(&mut self)->bool{
 
-        for (_,p) in self.players.iter_mut(){
+        let now = self.now();
+        let tokens : Vec<PlayerToken> = self.players.keys().cloned().collect();
 
+        for tok in tokens{
+            let (next, energy, ready) = match self.players.get(&tok){
+                Some(player)=>match player.queue.first(){
+                    Some(next)=>{
+                        let ready = player.cooldowns.get(&next.start).map(|until| *until <= now).unwrap_or(true);
+                        (*next, player.energy, ready)
+                    }
+                    None=>continue,
+                },
+                None=>continue,
+            };
 
-            let fee = p.value/10;
-            let target_value;
+            let needed = f32::max(next.min_energy, self.move_cost(tok, next.start, next.end, next.spawn));
+            if !ready || energy < needed{
+                continue
+            }
 
-            if p.number.0 == winner{
-                target_value = p.value + self.value - fee;
-            }else{
-                target_value = p.value - fee;
+            //either it goes through or it cant be made anymore, in both cases it leaves the queue
+            if let Some(player) = self.players.get_mut(&tok){
+                player.queue.remove(0);
             }
+            match self.execute_move(tok, next.start, next.end, next.spawn){
+                MoveResult::End { winner: _ }=>return true,
+                MoveResult::Fail=>{
+                    println!("dropped queued move {} of {}",next.id,tok);
+                    if let Some(player) = self.players.get_mut(&tok){
+                        player.send_event(GameMessage::QueueDropped { id: next.id });
+                    }
+                }
+                MoveResult::Success=>{}
+            }
+        }
+        false
+    }
 
+    //remember a move for the end of the round, it is checked when it is played
+    fn plan_move(&mut self, token: PlayerToken, start:i32, end:i32, spawn:i32)->MoveResult{
 
-            let fut = database::set_player_score(&p.id.0,target_value,secret);
-            if let Err(e) = fut.await{
-                println!("error setting scores {}",e.0);
-            }
+        if self.status != GameStatus::Running || self.turn_done.contains(&token){
+            return MoveResult::Fail
+        }
+        let number = match self.players.get(&token){
+            Some(player)=>player.number,
+            None=>return MoveResult::Fail,
+        };
+
+        let limit = self.rules.actions_per_round.max(1) as usize;
+        let planned = self.planned.entry(token).or_insert(vec![]);
+        if planned.len() >= limit{
+            return MoveResult::Fail
+        }
+        planned.push(PlannedMove { number, token, start, end, spawn });
+        println!("planned move {} of player {:?}",planned.len(),number);
+
+        if planned.len() >= limit{
+            return self.end_turn(token)
         }
+        MoveResult::Success
     }
 
-    pub fn get_lobby_info(&self)-> GameMessage{
+    //the player is done planning, once everybody is the round is played
+    pub fn end_turn(&mut self, token: PlayerToken)->MoveResult{
 
-        let mut player_list = vec![];
+        if !self.rules.turn_based || self.status != GameStatus::Running || !self.players.contains_key(&token){
+            return MoveResult::Fail
+        }
+        self.turn_done.insert(token);
 
-        for player in self.players.values(){
-            player_list.push((player.id.0.clone(),player.number.0))
+        if self.players.keys().all(|tok| self.turn_done.contains(tok)){
+            return self.resolve_round()
         }
+        MoveResult::Success
+    }
 
-        GameMessage::Lobby { players: player_list }
-        
+    //play the planned moves step by step, everybodys first move together, then the second and so on
+    //moves of a step are judged on the board as it was before the step:
+    //- moves that were not possible on that board fail
+    //- pieces of different players going for the same tile or swapping places all stay where they are
+    //- a piece going for a tile whose piece makes a move of its own in the same step stays,
+    //  the other piece got away or is busy shooting or spawning and cant be taken right then
+    //what is left can still depend on the order: sliders crossing tiles others just left or entered,
+    //and the move of a player whose king was taken earlier in the step, which is dropped.
+    //for those the player who goes first changes every round so nobody is always ahead
+    fn resolve_round(&mut self)->MoveResult{
+
+        println!("resolving round {} of game {}",self.round,self.id);
+
+        let mut planned = std::mem::take(&mut self.planned);
+        self.turn_done.clear();
+        self.round += 1;
+        self.round_start = Some(self.now());
+
+        let steps = planned.values().map(|moves| moves.len()).max().unwrap_or(0);
+        let mut results = vec![];
+        let mut outcome = MoveResult::Success;
+
+        'steps: for step in 0..steps{
+
+            let mut moves : Vec<PlannedMove> = planned.values_mut().filter_map(|moves| moves.get(step).cloned()).collect();
+            let first = (self.round as usize) % MAXPLAYERCOUNT as usize;
+            moves.sort_by_key(|m| (m.number.0 as usize + MAXPLAYERCOUNT as usize - first) % MAXPLAYERCOUNT as usize);
+
+            let mut cancelled : Vec<bool> = moves.iter().map(|m| !self.planned_move_possible(m)).collect();
+            for i in 0..moves.len(){
+                for j in 0..moves.len(){
+                    let (a, b) = (moves[i], moves[j]);
+                    if i == j || a.number == b.number{
+                        continue
+                    }
+                    let same_target = a.end == b.end;
+                    //covers swaps as well, both pieces go for a piece that is busy
+                    let chasing = a.end == b.start && self.planned_move_possible(&b);
+                    if same_target{
+                        cancelled[i] = true;
+                        cancelled[j] = true;
+                    }
+                    if chasing{
+                        cancelled[i] = true;
+                    }
+                }
+            }
+
+            for (m, cancelled) in moves.iter().zip(cancelled){
+                if cancelled{
+                    results.push((m.number.0, m.start, m.end, m.spawn, false));
+                    continue
+                }
+                if !self.players.contains_key(&m.token){
+                    //eliminated earlier this round
+                    continue
+                }
+                let res = self.execute_move(m.token, m.start, m.end, m.spawn);
+                results.push((m.number.0, m.start, m.end, m.spawn, matches!(res, MoveResult::Success | MoveResult::End { .. })));
+                if let MoveResult::End { winner: _ } = res{
+                    outcome = res;
+                    break 'steps
+                }
+            }
+        }
+        planned.clear();
+
+        //everybody learns about the moves they can see
+        let tokens : Vec<PlayerToken> = self.players.keys().cloned().collect();
+        for tok in tokens{
+            let number = self.players.get(&tok).unwrap().number;
+            let visible = results.iter()
+                .filter(|(num, start, end, _, _)|{
+                    *num == number.0
+                    || (*start >= 0 && *start < LAST_TILE && self.sees(number, Pos::from_num(*start)))
+                    || (*end >= 0 && *end < LAST_TILE && self.sees(number, Pos::from_num(*end)))
+                })
+                .cloned()
+                .collect();
+            let msg = GameMessage::Round { round: self.round - 1, results: visible };
+            if let Some(player) = self.players.get_mut(&tok){
+                player.send_event(msg);
+            }
+        }
+        outcome
     }
 
-    pub fn is_open(&self)->bool{
-        (self.status == GameStatus::Lobby ) && (self.players.len() as i32) < MAXPLAYERCOUNT
+    //could the move be made on the board as it is, without looking at energy or cooldowns
+    fn planned_move_possible(&self, m: &PlannedMove)->bool{
+        if m.start == m.end || m.start < 0 || m.end < 0 || m.start >= LAST_TILE || m.end >= LAST_TILE{
+            return false
+        }
+        match self.board[m.start as usize].status{
+            TileStatus::Taken(pn, Piece::KING) if pn == m.number && m.spawn != 0=>self.board[m.end as usize].status.is_empty(),
+            TileStatus::Taken(pn, piece) if pn == m.number=>{
+                self.move_is_possible(Pos::from_num(m.start), Pos::from_num(m.end), &m.token, piece)
+            }
+            _=>false,
+        }
     }
 
-    pub fn make_move(&mut self,token: u32,start:i32,end:i32,spawn:i32)->MoveResult{   
+    //check and carry out a move right away
+    fn execute_move(&mut self,token: u32,start:i32,end:i32,spawn:i32)->MoveResult{   
 
 
         println!("trying to make move {} {} {} ",start,end,spawn);
 
-        if start == end || start <0 || end < 0{ 
+        if start == end || start <0 || end < 0 || start >= LAST_TILE || end >= LAST_TILE{ 
             return MoveResult::Fail
         }
 
@@ -418,12 +1691,8 @@ impl Game{
         if let Some(player) = self.players.get_mut(&token){
 
 
-            start_pos = player.relative_to_absolute(start);
-            end_pos = player.relative_to_absolute(end);
-
-            if start_pos.n < 0 || end_pos.n < 0 || start_pos.n >= LAST_TILE || end_pos.n >= LAST_TILE{
-                return MoveResult::Fail
-            }
+            start_pos = Pos::from_num(start);
+            end_pos = Pos::from_num(end);
 
             player.view_changed = true;
             player_num = player.number;
@@ -431,18 +1700,13 @@ impl Game{
 
 
 
-            let time_diff = player.last_move_time.elapsed().as_secs_f32();
-
-            if time_diff < MOVE_DELAY{
-                return MoveResult::Fail
+            if let Some(ready) = player.cooldowns.get(&start_pos.n).filter(|_| !self.rules.turn_based){
+                if *ready > self.clock.now(){
+                    println!("piece not ready");
+                    return MoveResult::Fail
+                }
             }
 
-            player.last_move_time = time::Instant::now();
-
-
-            energy += time_diff * ENERGY_REGEN;
-            energy = f32::min(energy, 10.);
-
 
             println!("real move {:?} {:?} ", start_pos, end_pos);
 
@@ -453,14 +1717,15 @@ impl Game{
 
         if energy < 1. {
             println!("no energy");
-            self.players.entry(token).and_modify(|p|{
-                p.energy = energy;
-            });
-
             return MoveResult::Fail
         }
         
         
+        let captured_king = match self.board[end_pos.n as usize].status{
+            TileStatus::Taken(pn, Piece::KING) if !self.allied(pn, player_num) => Some(pn),
+            _=>None,
+        };
+
         let origin_status = self.board[start_pos.n as usize].status;
         let succ = match origin_status{
 
@@ -473,7 +1738,7 @@ impl Game{
 
                     match piece{
 
-                        Piece::King=>{
+                        Piece::KING=>{
                             //king action
 
                             match spawn{
@@ -481,72 +1746,15 @@ impl Game{
                                     //try move the king
                                     println!("try tp move king");
 
-                                    if let TileStatus::Taken(num, _) =  self.board[end_pos.n as usize].status {
-                                        if num == player_num {
-                                            return MoveResult::Fail
-                                        }else{
-                                            println!("{:?} not playernum {:?}",num,player_num)
-                                        }
+                                    if self.piece_move(start_pos, end_pos, &token, piece){
+                                        energy -= 1.;
+                                        self.players.entry(token.clone()).and_modify(|player| {
+                                            player.king_pos = end_pos;
+                                        });
+                                        true
                                     }else{
-                                        println!("end empty")
-                                    }
-
-                                    energy -= 1.;
-
-                                    let mut succ = false;
-
-                                    for dir in [STRAIGHTS,DIAGONALS].concat(){
-
-                                        if let Ok(target) = start_pos.step(dir.0, dir.1){
-                                            if target.n == end_pos.n{
-                                                //move the king
-
-
-                                                // if self.board[end_pos.n as usize] == TileStatus::Treasure{
-                                                if Some(end_pos.n) == self.treasure_position{
-                                                    self.take_treasure(&token)
-                                                }
-
-                                                if let TileStatus::Taken(pn, Piece::King) = self.board[end_pos.n as usize].status{
-
-                                                    if let Some(holder) = self.treasure_holder {
-                                                        if holder == pn{
-                                                            self.take_treasure(&token)
-                                                        }
-                                                    }
-                                                };
-
-                                                self.players.entry(token.clone()).and_modify(|player| {
-                                                    player.king_pos = target;
-                                                });
-
-                                                if self.board[end_pos.n as usize].ground == TileGround::Safe && matches!(self.board[end_pos.n as usize].status, TileStatus::Taken(_,_)){
-                                                    return MoveResult::Fail;
-                                                }
-
-                                                if end_pos.x == 0{
-                                                    if self.players.get(&token).expect("lost player").got_treasure{
-                                                        let end_message = GameMessage::End { winning_number: num.0, value: self.value};
-                                                        self.broadcast(end_message.clone());
-                                                        self.conclusion = Some(end_message);
-                                                        return MoveResult::End{winner:num.0};
-                                                    }
-                                                }
-
-
-                                                self.board[end_pos.n as usize].status = self.board[start_pos.n as usize].status;
-                                                self.board[start_pos.n as usize].status = TileStatus::Empty;
-                                                if self.board[start_pos.n as usize].ground == TileGround::Treasure{
-                                                    self.board[start_pos.n as usize].ground = TileGround::Ground
-                                                }
-
-
-                                                succ = true;
-                                                break
-                                            }
-                                        }
+                                        false
                                     }
-                                    succ
                                 }
                                 _=> self.spawn_piece(&mut energy, spawn, end_pos, start_pos, token.clone(), player_num)
                             }
@@ -555,8 +1763,26 @@ impl Game{
                         _=>{
 
                             
+                            //pawns name the piece they want to become in the spawn field
+                            let promotion = if piece == Piece::PAWN && spawn != 0{
+                                match self.promotion_cost(end_pos, token, spawn){
+                                    Some((new_piece, cost)) if energy >= 1. + cost => Some((new_piece, cost)),
+                                    _=>{
+                                        println!("cant promote to {}",spawn);
+                                        return MoveResult::Fail
+                                    }
+                                }
+                            }else{
+                                None
+                            };
+
                             if self.piece_move(start_pos, end_pos, &token, piece){
                                 energy -= 1.;
+
+                                if let Some((new_piece, cost)) = promotion{
+                                    energy -= cost;
+                                    self.board[end_pos.n as usize].status = TileStatus::Taken(player_num, new_piece);
+                                }
                                 
                                 true
                             }else{
@@ -573,13 +1799,39 @@ impl Game{
                 false
             }
 
+            _=>{
+                println!("cant move abandoned piece");
+                false
+            }
+
         };
         self.players.entry(token).and_modify(|p|{
             p.energy = energy;
         });
 
         if succ{
+            self.start_cooldowns(token, player_num, [start_pos, end_pos]);
+
+            if let Some(victim) = captured_king{
+                //the treasure falls where the king died and goes to whoever took it
+                self.drop_treasures(victim, end_pos);
+            }
+            //a ranged shot leaves the treasure lying where the king fell
+            if matches!(self.board[end_pos.n as usize].status, TileStatus::Taken(pn, _) if pn == player_num){
+                self.collect_treasures(player_num, end_pos);
+            }
+
+            let end_tile = self.board[end_pos.n as usize];
+            if end_tile.ground == TileGround::Safe && end_tile.status == TileStatus::Taken(player_num, Piece::KING){
+                if let Some(res) = self.bank_treasures(token){
+                    return res
+                }
+            }
+
             self.update_views(start_pos, end_pos);
+            if let Some(victim) = captured_king{
+                return self.eliminate_player(victim)
+            }
             MoveResult::Success
         }else{
             MoveResult::Fail
@@ -587,8 +1839,42 @@ impl Game{
     
     }
 
+    //whatever of the player now stands on these tiles has to wait its cooldown
+    fn start_cooldowns(&mut self, token: PlayerToken, number: PlayerNumber, tiles: [Pos;2]){
+        let now = self.now();
+        let mut ready = vec![];
+        for pos in tiles{
+            let until = match self.board[pos.n as usize].status{
+                TileStatus::Taken(pn, piece) if pn == number=>{
+                    Some(now + time::Duration::from_secs_f32(piece.def().cooldown))
+                }
+                _=>None,
+            };
+            ready.push((pos.n, until));
+        }
+        if let Some(player) = self.players.get_mut(&token){
+            for (n, until) in ready{
+                match until{
+                    Some(until)=>player.cooldowns.insert(n, until),
+                    None=>player.cooldowns.remove(&n),
+                };
+            }
+        }
+    }
+
+    fn can_spawn(&self, piece: Piece)->bool{
+        let def = piece.def();
+        def.spawnable && (def.terrain != Terrain::Water || self.rules.mode.has_water())
+    }
+
     fn spawn_piece(&mut self, energy: &mut f32, spawn: i32, end_pos: Pos, start_pos: Pos, token: PlayerToken, player_num: PlayerNumber) -> bool {
-        let piece = Piece::from_num(spawn);
+        let piece = match Piece::from_num(spawn){
+            Some(piece) if self.can_spawn(piece)=>piece,
+            _=>{
+                println!("cant spawn {}",spawn);
+                return false
+            }
+        };
         println!("spaning {:?}",piece);
         
         let cost = piece.get_cost() as f32;
@@ -616,67 +1902,70 @@ impl Game{
         }
     }
 
+    //the piece a pawn arriving on this tile turns into and what that costs, None if it cant promote there
+    fn promotion_cost(&self, pos: Pos, token: PlayerToken, spawn: i32)->Option<(Piece, f32)>{
+
+        if self.rules.pawns != PawnVariant::Directional{
+            return None
+        }
+        let new_piece = Piece::from_num(spawn).filter(|p| self.can_spawn(*p) && *p != Piece::PAWN)?;
+        let forward = self.players.get(&token)?.forward;
+
+        let center = self.treasures.first()?.home;
+        let near_treasure = (pos.x - center.x).abs() <= 1 && (pos.y - center.y).abs() <= 1;
+        let far_edge = pos.step(forward.0, forward.1).is_err();
+        if !near_treasure && !far_edge{
+            return None
+        }
+
+        if self.rules.free_promotion{
+            Some((new_piece, 0.))
+        }else{
+            Some((new_piece, new_piece.get_cost().saturating_sub(Piece::PAWN.get_cost()) as f32))
+        }
+    }
+
     fn piece_move(&mut self, start: Pos, end: Pos, token: &PlayerToken, piece: Piece) -> bool {
 
-        if self.board[end.n as usize].ground == TileGround::Safe && matches !(self.board[end.n as usize].status, TileStatus::Taken(_, _)){
+        if self.board[end.n as usize].ground == TileGround::Safe && !self.board[end.n as usize].status.is_empty(){
             //cant kill on safe ground
             return false
         }
 
         if self.move_is_possible(start, end, token, piece){
 
-
-            // if self.board[end.n as usize] == TileStatus::Treasure
-            if Some(end.n) == self.treasure_position
-            {
-                self.take_treasure(token);
-            }else if let TileStatus::Taken(pn,Piece::King) = self.board[end.n as usize].status{
-                if let Some(holder) = self.treasure_holder{
-                    if holder == pn{
-                        self.take_treasure(token)
-                    }
-                }
+            if self.piece_def(piece).ranged && !self.board[end.n as usize].status.is_empty(){
+                //shoot the target and stay put
+                self.board[end.n as usize].status = TileStatus::Empty;
+                return true
             }
 
             self.board[end.n as usize].status = self.board[start.n as usize].status;
             self.board[start.n as usize].status = TileStatus::Empty;
-            if self.board[start.n as usize].ground == TileGround::Treasure{
-                self.board[start.n as usize].ground = TileGround::Ground
-            }
 
             return true
         }
         return false
     }
 
-    fn take_treasure(&mut self, token: &u32) {
-        self.players.entry(token.clone()).and_modify(|p|{
-            p.got_treasure = true;
-            self.treasure_holder = Some(p.number);
-        });
-    }
-
     pub fn request_update(&mut self, player_token:u32) -> Result<Option<GameMessage>,oneshot::Receiver<GameMessage>>{
 
         let token = player_token;
+        self.seen(token);
 
         match self.players.get_mut(&token){
-            Some(mut player)=>{
+            Some(player)=>{
+
+                if let Some(event) = player.events.pop_front(){
+                    return Ok(Some(event))
+                }
 
                 if player.view_changed{
                     player.view_changed = false;
 
                     match self.status{
                         GameStatus::Lobby=>{
-                            let mut playerlist  = vec![];
-                            for (_,p) in  self.players.iter(){
-                                playerlist.push((p.id.0.clone(), p.number.0));
-                            } 
-                            Ok(Some(
-                                GameMessage::Lobby{
-                                    players:playerlist
-                                }
-                            ))
+                            Ok(Some(self.get_lobby_info()))
                         }
                         GameStatus::Running=>{
                             let gs = self.get_current_view(&token);
@@ -703,13 +1992,19 @@ impl Game{
 
             }
             None=>{
-                //player not part of this game
-                Ok(None)
+                //player not part of this game anymore
+                Ok(self.departed.get(&token).cloned())
             }
         }
 
     }
 
+    fn broadcast_event(&mut self, msg: GameMessage){
+        for player in self.players.values_mut(){
+            player.send_event(msg.clone())
+        }
+    }
+
     fn broadcast(&mut self, msg: GameMessage){
 
         for player in self.players.values_mut(){
@@ -723,12 +2018,15 @@ impl Game{
 
         let mut tokens :Vec<PlayerToken> = vec![];
         
+        //the owner of the moved piece and their team mates see something else now
+        let mover = match self.board[end.n as usize].status{
+            TileStatus::Taken(pn,_)=>Some(pn),
+            _=>None,
+        };
+
         for player in self.players.values(){
-            if  ((player.king_pos.x - end.x).abs() <= VIEW_RADIUS &&
-                (player.king_pos.y - end.y).abs() <= VIEW_RADIUS) ||
-                ((player.king_pos.x - start.x).abs() <= VIEW_RADIUS &&
-                (player.king_pos.y - start.y).abs() <= VIEW_RADIUS)
-            {
+            let moved_own = mover.map(|pn| self.allied(pn, player.number)).unwrap_or(false);
+            if moved_own || self.sees(player.number, start) || self.sees(player.number, end){
                 tokens.push(player.token.clone());
             }
         }
@@ -753,66 +2051,125 @@ impl Game{
         }
     }
 
+    //is the tile in sight of any piece of the player or the player's team mates
+    fn sees(&self, number: PlayerNumber, pos: Pos)->bool{
+        for n in 0..LAST_TILE{
+            if let TileStatus::Taken(pn, piece) = self.board[n as usize].status{
+                let from = Pos::from_num(n);
+                let sight = piece.get_sight();
+                if (from.x - pos.x).abs() <= sight && (from.y - pos.y).abs() <= sight && self.allied(pn, number){
+                    return true
+                }
+            }
+        }
+        false
+    }
+
+    //every tile in sight of the player and the player's team mates
+    fn vision(&self, number: PlayerNumber)->Vec<bool>{
+        let mut res = vec![false; LAST_TILE as usize];
+        for n in 0..LAST_TILE{
+            if let TileStatus::Taken(pn, piece) = self.board[n as usize].status{
+                if !self.allied(pn, number){
+                    continue
+                }
+                let from = Pos::from_num(n);
+                let sight = piece.get_sight();
+                for dx in -sight..=sight{
+                    for dy in -sight..=sight{
+                        if let Ok(target) = from.step(dx, dy){
+                            res[target.n as usize] = true;
+                        }
+                    }
+                }
+            }
+        }
+        res
+    }
+
     fn get_current_view(&mut self, token: &PlayerToken)->Option<GameMessage>{
         // let player = self.players.get_mut(&PlayerID(player));
         // let player = player.unwrap();
 
-        if let Some(player) = self.players.get_mut(token){
-
-            player.view_changed = false;
-
-            let mut res:[Option<Tile>;81] = [None;81];
-
-            let margin = 4;
-
-            let mut index = 0;
-            for i in 0..9{
-                for j in 0..9{
-
-                    let x = i + player.king_pos.x as i32 -margin;
-                    let y = j + player.king_pos.y as i32 -margin;
-
-                    let nums;
-
-
-                    if x <0 || y <0 || x >= BOARD_SIZE || y >= BOARD_SIZE{
-                        nums = None;
-                    }else{
-
-
-                        let n = x + y * BOARD_SIZE as i32;
-
-                        let tile = self.board[n as usize];
-
-                        nums = Some(tile)
+        let now = self.now();
+        let (time_left, sudden_death) = self.clock().unwrap_or((-1., false));
+        let (round, round_left) = if self.rules.turn_based{
+            (self.round, f32::max(self.round_left(), 0.))
+        }else{
+            (-1, -1.)
+        };
+        let planned = self.planned.get(token).map(|moves| moves.len() as i32).unwrap_or(0);
+        let mut disconnected : Vec<i32> = self.players.values().filter(|p| !p.connected).map(|p| p.number.0).collect();
+        disconnected.sort();
+        let revealed = self.revealed_treasures();
+        let (flood_in, flood_ring) = match self.flood_schedule(){
+            Some((_, next_in)) if next_in >= 0. => (next_in, self.flooded),
+            _=>(-1., -1),
+        };
 
+        let number = self.players.get(token)?.number;
+        let vision = self.vision(number);
+        let mut data = vec![];
+        for n in 0..LAST_TILE{
+            if vision[n as usize]{
+                data.push((n, self.board[n as usize]));
+            }
+        }
 
-                    }
+        if let Some(player) = self.players.get_mut(token){
 
-                    res[index] = nums;
-                    index+=1;
+            player.view_changed = false;
 
+            for (n, tile) in data.iter(){
+                player.memory.insert(*n, (*tile, now));
+            }
+            //tiles out of sight only ever show what the player saw there
+            let mut remembered = vec![];
+            for (n, (tile, seen)) in player.memory.iter(){
+                if !vision[*n as usize]{
+                    remembered.push((*n, *tile, now.saturating_duration_since(*seen).as_secs_f32()));
                 }
             }
 
+            let energy = player.energy;
 
-            let energy = player.energy + player.last_move_time.elapsed().as_secs_f32() * ENERGY_REGEN;
+            let queue = player.queue.iter().map(|m| (m.id, m.start, m.end, m.spawn, m.min_energy)).collect();
+            let mut cooldowns = vec![];
+            for (n, ready) in player.cooldowns.iter(){
+                if *ready > now{
+                    cooldowns.push((*n, (*ready - now).as_secs_f32()));
+                }
+            }
 
 
-            let holder = match self.treasure_holder{
-                None=>{
-                    -1
+            let mut holder = -1;
+            let mut got_treasure = false;
+            for treasure in self.treasures.iter(){
+                if let TreasureState::Carried(num) = treasure.state{
+                    holder = num.0;
+                    got_treasure |= num == player.number;
                 }
-                Some(num)=>{
-                    num.0
-                }
-            };
+            }
             Some(GameMessage::State { 
-                data: Vec::from(res), 
+                data, 
+                remembered,
                 offset: (player.king_pos.x,player.king_pos.y), 
                 energy: energy, 
-                got_treasure : player.got_treasure,
+                cooldowns,
+                queue,
+                got_treasure,
                 treasure_holder : holder,
+                banked : player.banked,
+                time_left,
+                sudden_death,
+                round,
+                round_left,
+                planned,
+                disconnected,
+                spectators : self.spectators.count(),
+                treasures : revealed,
+                flood_in,
+                flood_ring,
             })
         } else{
             None
@@ -820,73 +2177,55 @@ impl Game{
 
     }
 
+    //the definition the rules use for this piece
+    fn piece_def(&self, piece: Piece)->&'static PieceDef{
+        if piece == Piece::PAWN && self.rules.pawns == PawnVariant::Directional{
+            registry().directional_pawn()
+        }else{
+            piece.def()
+        }
+    }
+
     fn move_is_possible(&self, start:Pos,end:Pos,token:&PlayerToken,piece:Piece)->bool{
 
         if let Some(player) = self.players.get(token){
             println!("move possible?");
-            match piece{
-                Piece::Knight=>{
-                    for hop in KNIGHTHOPS.iter(){
-                        let target = Pos::from_ints(start.x + hop.0, start.y + hop.1);
-                        if pos_is_on_board(target) && player.can_see(target) && target == end{
-
-                            match self.board[target.n as usize].status{
-                                TileStatus::Taken(pn,_)=>{
-                                    if pn != player.number{
-                                        return true
-                                    }else{
 
-                                        println!("field blocked");
-                                        return false
-                                    }
-                                }
-                                TileStatus::Empty =>{
-                                    return true
-                                }
-                            }
-                        }
-                    }
-                    println!("no match ing knight mvoe");
-                    false
-                }
-                Piece::Pawn=>{
-                    for hop in STRAIGHTS.iter(){
-                        let target = Pos::from_ints(start.x + hop.0, start.y + hop.1);
-                        if pos_is_on_board(target) && player.can_see(target) && target == end{
+            let number = player.number;
+            let def = self.piece_def(piece);
 
-                            if let TileStatus::Taken( _,_ ) = self.board[target.n as usize].status{
-                                return false
-                            }else{
-                                return true
-                            }
-                        }
-                    }
-                    for hop in DIAGONALS.iter(){
-                        let target = Pos::from_ints(start.x + hop.0, start.y + hop.1);
-                        if pos_is_on_board(target) && player.can_see(target) && target == end{
+            //ships stay on the water, everything else on land
+            let walkable = |pos:Pos|{
+                let wet = self.board[pos.n as usize].ground == TileGround::Water;
+                wet == (def.terrain == Terrain::Water)
+            };
 
-                            if let TileStatus::Taken( other,_ ) = self.board[target.n as usize].status{
-                                return other != player.number
-                            }else{
-                                return false
-                            }
-                        }
-                    }
-                    false
+            if !pos_is_on_board(end) || !self.sees(number, end) || !walkable(end){
+                println!("cant reach {:?}",end);
+                return false
+            }
 
-                }
-                Piece::Bishop=>{
+            let forward = if def.directional { player.forward } else { (1,0) };
 
-                    self.check_lines(start, end, player, DIAGONALS)
-                }
-                Piece::Queen=>{
-                    self.check_lines(start, end ,player, DIAGONALS)||
-                    self.check_lines(start, end, player, STRAIGHTS)
-                }
-                Piece::Rook=>{
-                    self.check_lines(start, end, player, STRAIGHTS)
+            //tiles on the way have to be in sight, walkable and free
+            let open = |x:i32, y:i32|{
+                let pos = Pos::from_ints(x, y);
+                pos_is_on_board(pos)
+                && self.sees(number, pos)
+                && walkable(pos)
+                && self.board[pos.n as usize].status.is_empty()
+            };
+
+            if self.board[end.n as usize].status.is_empty(){
+                def.moves.reaches((start.x, start.y), (end.x, end.y), forward, &open)
+            }else if self.can_enter(end, number){
+                match &def.captures{
+                    Some(captures)=>captures.reaches((start.x, start.y), (end.x, end.y), forward, &open),
+                    None=>false,
                 }
-                _=>{panic!()}   
+            }else{
+                println!("field blocked");
+                false
             }
 
         }else {
@@ -894,50 +2233,136 @@ impl Game{
         }
     }
 
-    fn check_lines(&self,start:Pos,end:Pos,player:&Player,dirs: [(i32,i32);4])-> bool{
+}
+
+fn pos_is_on_board(pos:Pos)->bool{
+    let res = !(pos.x < 0 || pos.y < 0 || pos.x >= BOARD_SIZE || pos.y >= BOARD_SIZE);
+    if ! res {
+        println!("not on board")
+    }
+    res
+}
+
+//pawns walk from the starting side towards the middle of the island
+fn forward_from(start:Pos)->(i32,i32){
+    let dx = BOARD_SIZE/2 - start.x;
+    let dy = BOARD_SIZE/2 - start.y;
+    if dx.abs() >= dy.abs(){
+        (if dx < 0 {-1} else {1}, 0)
+    }else{
+        (0, dy.signum())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
 
-        println!("looking for {:?}",end);
+    struct FixedClock(time::Instant);
 
-        for dir in dirs.iter(){
+    impl Clock for FixedClock{
+        fn now(&self)->time::Instant{
+            self.0
+        }
+    }
 
-            let mut target = start;
-            loop{
-                target = Pos::from_ints(target.x + dir.0, target.y + dir.1);
+    //a running turn based game of two players that each get one move per round
+    fn round_game(round: i32)->(Game, PlayerToken, PlayerToken){
+        let rules = GameRules { turn_based: true, actions_per_round: 1, ..GameRules::default() };
+        let mut game = Game::new(0, rules, Arc::new(FixedClock(time::Instant::now())));
+        game.add_player("a".to_string(), 100).unwrap();
+        game.add_player("b".to_string(), 100).unwrap();
+        game.begin();
+        game.round = round;
+
+        let mut tokens = [0; 2];
+        for player in game.players.values_mut(){
+            player.energy = MAX_ENERGY;
+            tokens[player.number.0 as usize] = player.token;
+        }
+        (game, tokens[0], tokens[1])
+    }
 
-                println!("{:?}",target);
+    fn put(game: &mut Game, x: i32, y: i32, number: i32, piece: Piece){
+        game.board[Pos::from_ints(x, y).n as usize].status = TileStatus::Taken(PlayerNumber(number), piece);
+    }
 
-                if pos_is_on_board(target) && player.can_see(target){
+    fn at(game: &Game, x: i32, y: i32)->TileStatus{
+        game.board[Pos::from_ints(x, y).n as usize].status
+    }
 
-                    if target == end{
-                        if let TileStatus::Taken(pn,_) = self.board[target.n as usize].status{
-                            println!("not possible cant hit own unit");
-                            return pn != player.number
-                        }else {
-                            println!("move is possible");
-                            return true
-                        }
-                    }
-                    if let TileStatus::Taken(_,_) = self.board[target.n as usize].status{
+    fn plan(game: &mut Game, token: PlayerToken, from: (i32, i32), to: (i32, i32)){
+        let number = game.players[&token].number;
+        let (start, end) = (Pos::from_ints(from.0, from.1).n, Pos::from_ints(to.0, to.1).n);
+        game.planned.insert(token, vec![PlannedMove { number, token, start, end, spawn: 0 }]);
+    }
 
-                        break
-                    }
-                }else{
-                    println!("{:?}",target);
-                    break
-                }
-            }
+    //every order of players the round can start with
+    fn rounds()->std::ops::Range<i32>{
+        0..MAXPLAYERCOUNT
+    }
+
+    #[test]
+    fn same_target_stops_both(){
+        for round in rounds(){
+            let (mut game, a, b) = round_game(round);
+            put(&mut game, 5, 2, 0, Piece::ROOK);
+            put(&mut game, 5, 6, 1, Piece::ROOK);
+            plan(&mut game, a, (5, 2), (5, 4));
+            plan(&mut game, b, (5, 6), (5, 4));
+            game.resolve_round();
+
+            assert_eq!(at(&game, 5, 2), TileStatus::Taken(PlayerNumber(0), Piece::ROOK));
+            assert_eq!(at(&game, 5, 6), TileStatus::Taken(PlayerNumber(1), Piece::ROOK));
+            assert_eq!(at(&game, 5, 4), TileStatus::Empty);
         }
-        false
+    }
 
+    #[test]
+    fn swap_stops_both(){
+        for round in rounds(){
+            let (mut game, a, b) = round_game(round);
+            put(&mut game, 5, 2, 0, Piece::ROOK);
+            put(&mut game, 5, 3, 1, Piece::ROOK);
+            plan(&mut game, a, (5, 2), (5, 3));
+            plan(&mut game, b, (5, 3), (5, 2));
+            game.resolve_round();
+
+            assert_eq!(at(&game, 5, 2), TileStatus::Taken(PlayerNumber(0), Piece::ROOK));
+            assert_eq!(at(&game, 5, 3), TileStatus::Taken(PlayerNumber(1), Piece::ROOK));
+        }
     }
 
-}
+    #[test]
+    fn piece_that_moves_away_gets_away(){
+        for round in rounds(){
+            let (mut game, a, b) = round_game(round);
+            put(&mut game, 5, 2, 0, Piece::ROOK);
+            put(&mut game, 5, 4, 1, Piece::ROOK);
+            plan(&mut game, a, (5, 2), (5, 4));
+            plan(&mut game, b, (5, 4), (7, 4));
+            game.resolve_round();
+
+            assert_eq!(at(&game, 5, 2), TileStatus::Taken(PlayerNumber(0), Piece::ROOK));
+            assert_eq!(at(&game, 5, 4), TileStatus::Empty);
+            assert_eq!(at(&game, 7, 4), TileStatus::Taken(PlayerNumber(1), Piece::ROOK));
+        }
+    }
 
-fn pos_is_on_board(pos:Pos)->bool{
-    let res = !(pos.x < 0 || pos.y < 0 || pos.x >= BOARD_SIZE || pos.y >= BOARD_SIZE);
-    if ! res {
-        println!("not on board")
+    #[test]
+    fn shooting_piece_cant_be_taken_in_the_same_step(){
+        for round in rounds(){
+            let (mut game, a, b) = round_game(round);
+            put(&mut game, 5, 2, 0, Piece::ROOK);
+            put(&mut game, 8, 4, 0, Piece::PAWN);
+            put(&mut game, 5, 4, 1, Piece::CANNON);
+            plan(&mut game, a, (5, 2), (5, 4));
+            plan(&mut game, b, (5, 4), (8, 4));
+            game.resolve_round();
+
+            assert_eq!(at(&game, 5, 2), TileStatus::Taken(PlayerNumber(0), Piece::ROOK));
+            assert_eq!(at(&game, 5, 4), TileStatus::Taken(PlayerNumber(1), Piece::CANNON));
+            assert_eq!(at(&game, 8, 4), TileStatus::Empty);
+        }
     }
-    res
 }
-
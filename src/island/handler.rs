@@ -6,48 +6,469 @@ use tokio::sync::oneshot;
 
 use crate::{GameMessage, MoveResult};
 
-use super::game::Game;
+use std::sync::Arc;
+use std::time;
+
+use rand::Rng;
+use serde::Serialize;
+
+use super::clock::Clock;
+use super::game::{Game, LobbySummary, MINPLAYERCOUNT};
+use super::matchmaking::{QueueConfig, RatingQueue};
+use super::reaper::ReaperConfig;
+use super::rematch::Rematch;
+use super::parties::Parties;
+use super::rules::{GameMode, GameRules};
 use std::{collections::HashMap};
 
+//no 0/O or 1/I so codes can be read out loud
+const CODE_CHARS : &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH : usize = 6;
+const MAX_PAGE_SIZE : usize = 50;
+
+//which lobbies the browser wants to see
+#[derive(Clone, Debug, Default)]
+pub struct LobbyFilter{
+    pub mode: Option<GameMode>,
+    pub turn_based: Option<bool>,
+    pub max_stake: Option<i32>,
+    pub min_players: Option<i32>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LobbyPage{
+    pub lobbies: Vec<LobbySummary>,
+    pub page: usize,
+    //matching lobbies over all pages
+    pub total: usize,
+}
+
+
+//how a game that is gone ended, until every player came back for it
+struct Concluded{
+    msg: GameMessage,
+    tokens: Vec<u32>,
+    at: time::Instant,
+}
 
 pub struct MatchMaker{
 
     running_games: HashMap<i32,Game>,
     open_game_id : i32 ,
+    //ids are handed out in order, the open public lobby is just one of them
+    next_game_id : i32,
+    rules : GameRules,
+    clock : Arc<dyn Clock>,
+    queue : RatingQueue,
+    //invite codes of private lobbies and the game they lead to
+    private_lobbies : HashMap<String, i32>,
+    //the game each user is in, so nobody plays twice at once
+    active : HashMap<String, i32>,
+    //rematch offers by the id of the game that ended
+    rematches : HashMap<i32, Rematch>,
+    parties : Parties,
+    //conclusions of games that were taken away, by game id
+    concluded : HashMap<i32, Concluded>,
 }
 
 impl MatchMaker {
 
-    pub fn new()->Self{
+    pub fn new(rules: GameRules, queue: QueueConfig, clock: Arc<dyn Clock>)->Self{
         let mut res = MatchMaker { 
             running_games:HashMap::new(),
             open_game_id:-1,
+            next_game_id:0,
+            rules,
+            clock,
+            queue: RatingQueue::new(queue),
+            private_lobbies: HashMap::new(),
+            active: HashMap::new(),
+            rematches: HashMap::new(),
+            parties: Parties::new(),
+            concluded: HashMap::new(),
         };
         res.create_new_game();
         res
     }
 
-    pub fn create_new_game(&mut self) -> &mut Game {
-        self.open_game_id += 1;
-        let game = Game::new(self.open_game_id);
+    pub fn rules(&self)->&GameRules{
+        &self.rules
+    }
+
+    fn add_game(&mut self) -> i32 {
+        self.add_game_with(self.rules.clone())
+    }
+
+    fn add_game_with(&mut self, rules: GameRules) -> i32 {
+        let id = self.next_game_id;
+        self.next_game_id += 1;
+        let game = Game::new(id, rules, self.clock.clone());
         self.running_games.insert(game.id, game);
+        id
+    }
+
+    pub fn create_new_game(&mut self) -> &mut Game {
+        self.open_game_id = self.add_game();
 
         self.running_games.get_mut(&self.open_game_id).expect("cant find game that was just added (prob impossible)")
     }
 
+    fn new_code(&self)->String{
+        let mut rng = rand::thread_rng();
+        loop{
+            let code : String = (0..CODE_LENGTH).map(|_| CODE_CHARS[rng.gen_range(0..CODE_CHARS.len())] as char).collect();
+            if !self.private_lobbies.contains_key(&code){
+                return code
+            }
+        }
+    }
+
+    //the game the user is playing or waiting in, forgets games they are not part of anymore
+    pub fn current_game(&mut self, username: &str)->Option<i32>{
+        let id = *self.active.get(username)?;
+        match self.running_games.get(&id){
+            Some(game) if game.has_player(username)=>Some(id),
+            _=>{
+                self.active.remove(username);
+                None
+            }
+        }
+    }
+
+    fn already_playing(&mut self, username: &str)->Result<(),String>{
+        match self.current_game(username){
+            Some(id)=>Err(format!("already playing in game {}",id)),
+            None=>Ok(()),
+        }
+    }
+
+    //every join goes through here so the user is known to be in this game
+    fn seat(&mut self, game_id: i32, username: String, score: i32)->Result<GameMessage,String>{
+        let game = self.running_games.get_mut(&game_id).ok_or("cant find this game".to_string())?;
+        let msg = game.add_player(username.clone(), score)?;
+        self.queue.remove_name(&username);
+        self.active.insert(username, game_id);
+        Ok(msg)
+    }
+
+    //a lobby only reachable through its code, the creator is its host
+    pub fn create_private_lobby(&mut self, username: String, score: i32)->Result<GameMessage,String>{
+        self.already_playing(&username)?;
+
+        let id = self.add_game();
+        let code = self.new_code();
+        self.private_lobbies.insert(code.clone(), id);
+
+        let game = self.running_games.get_mut(&id).expect("cant get created game");
+        game.code = Some(code.clone());
+
+        match self.seat(id, username, score)?{
+            GameMessage::Join { game_id, number, token }=>Ok(GameMessage::Hosted { code, game_id, number, token }),
+            msg=>Ok(msg),
+        }
+    }
+
+    pub fn join_by_code(&mut self, username: String, score: i32, code: String)->Result<GameMessage,String>{
+        let code = code.to_uppercase();
+        let id = *self.private_lobbies.get(&code).ok_or("no lobby with this code".to_string())?;
+        self.already_playing(&username)?;
+
+        match self.running_games.get_mut(&id){
+            Some(game) if game.is_open()=>self.seat(id, username, score),
+            Some(_)=>Err("this lobby is full or already playing".to_string()),
+            None=>{
+                self.private_lobbies.remove(&code);
+                Err("no lobby with this code".to_string())
+            }
+        }
+    }
+
+    pub fn kick(&mut self, game_id: i32, host: u32, number: i32)->Result<(),String>{
+        match self.running_games.get_mut(&game_id){
+            Some(game) if game.in_lobby()=>game.kick(host, number),
+            Some(_)=>Err("game allready started".to_string()),
+            None=>Err("cant find this game".to_string()),
+        }
+    }
+
+    //open public lobbies, oldest first
+    pub fn list_lobbies(&self, filter: &LobbyFilter, page: usize, per_page: usize)->LobbyPage{
+        let mut lobbies : Vec<LobbySummary> = self.running_games.values()
+            .filter(|game| game.is_open() && game.code.is_none() && !game.queued)
+            .map(|game| game.summary())
+            .filter(|lobby|{
+                filter.mode.map_or(true, |mode| lobby.mode == mode)
+                && filter.turn_based.map_or(true, |tb| lobby.turn_based == tb)
+                && filter.max_stake.map_or(true, |stake| lobby.stake <= stake)
+                && filter.min_players.map_or(true, |count| lobby.player_count >= count)
+            })
+            .collect();
+        lobbies.sort_by_key(|lobby| lobby.id);
+
+        let total = lobbies.len();
+        let per_page = per_page.clamp(1, MAX_PAGE_SIZE);
+        let lobbies = lobbies.into_iter().skip(page.saturating_mul(per_page)).take(per_page).collect();
+
+        LobbyPage { lobbies, page, total }
+    }
+
+    //join a lobby picked from the browser
+    pub fn join_lobby(&mut self, game_id: i32, username: String, score: i32)->Result<GameMessage,String>{
+        self.already_playing(&username)?;
+
+        match self.running_games.get_mut(&game_id){
+            Some(game) if game.code.is_some()=>Err("this lobby is private".to_string()),
+            Some(game) if game.queued=>Err("this lobby was filled by the queue".to_string()),
+            Some(game) if game.is_open()=>self.seat(game_id, username, score),
+            Some(_)=>Err("this lobby is full or already playing".to_string()),
+            None=>Err("cant find this game".to_string()),
+        }
+    }
+
+    //the game this user is still in, with a fresh token
+    pub fn resume(&mut self, username: &str)->Result<GameMessage,String>{
+        let id = self.current_game(username).ok_or("no game to go back to".to_string())?;
+        self.running_games.get_mut(&id)
+            .and_then(|game| game.resume(username))
+            .ok_or("no game to go back to".to_string())
+    }
+
+    //public games by id, private ones only with their code
+    pub fn spectate(&mut self, game_id: i32, follow: i32)->Result<GameMessage,String>{
+        match self.running_games.get_mut(&game_id){
+            Some(game) if game.code.is_some()=>Err("this game is private".to_string()),
+            Some(game)=>game.spectate(follow),
+            None=>Err("cant find this game".to_string()),
+        }
+    }
+
+    pub fn spectate_by_code(&mut self, code: String, follow: i32)->Result<GameMessage,String>{
+        let id = *self.private_lobbies.get(&code.to_uppercase()).ok_or("no game with this code".to_string())?;
+        match self.running_games.get_mut(&id){
+            Some(game)=>game.spectate(follow),
+            None=>Err("no game with this code".to_string()),
+        }
+    }
+
+    pub fn follow(&mut self, game_id: i32, token: u32, follow: i32)->bool{
+        self.running_games.get_mut(&game_id).map_or(false, |game| game.follow(token, follow))
+    }
+
+    pub fn stop_spectating(&mut self, game_id: i32, token: u32)->bool{
+        self.running_games.get_mut(&game_id).map_or(false, |game| game.stop_spectating(token))
+    }
+
+    pub fn spectator_update(&mut self, game_id: i32, token: u32)-> Result<Result<Option<GameMessage>, oneshot::Receiver<GameMessage>>,NotFound<String>>{
+        match self.running_games.get_mut(&game_id){
+            Some(game)=>Ok(game.spectator_update(token)),
+            None=>Err(NotFound("cant get game".to_owned())),
+        }
+    }
+
+    pub fn accept_rematch(&mut self, game_id: i32, username: String, score: i32)->Result<GameMessage,String>{
+        self.already_playing(&username)?;
+
+        let now = self.clock.now();
+        let rematch = self.rematches.get_mut(&game_id).ok_or("no rematch offer for this game".to_string())?;
+        rematch.accept(username.clone(), score, now)?;
+
+        self.form_rematches();
+        self.rematch_status(game_id, &username)
+    }
+
+    pub fn rematch_status(&mut self, game_id: i32, username: &str)->Result<GameMessage,String>{
+        let now = self.clock.now();
+        self.rematches.get_mut(&game_id)
+            .ok_or("no rematch offer for this game".to_string())?
+            .status(game_id, username, now)
+    }
+
+    //the players that took a rematch get a private lobby with the old settings
+    fn form_rematches(&mut self){
+        let now = self.clock.now();
+        let ready : Vec<i32> = self.rematches.iter().filter(|(_, r)| r.ready(now)).map(|(id, _)| *id).collect();
+
+        for old_id in ready{
+            let (rules, players) = match self.rematches.get_mut(&old_id){
+                Some(rematch)=>(rematch.rules.clone(), rematch.form()),
+                None=>continue,
+            };
+
+            //whoever went on to another game in the meantime is left out
+            let mut seated = vec![];
+            for (name, score) in players{
+                match self.already_playing(&name){
+                    Ok(())=>seated.push((name, score)),
+                    Err(e)=>println!("cant seat {} for the rematch {}",name,e),
+                }
+            }
+            if seated.is_empty(){
+                continue
+            }
+
+            let id = self.add_game_with(rules);
+            let code = self.new_code();
+            self.private_lobbies.insert(code.clone(), id);
+            if let Some(game) = self.running_games.get_mut(&id){
+                game.code = Some(code);
+            }
+            println!("rematch of game {} in lobby {}",old_id,id);
+
+            for (name, score) in seated{
+                match self.seat(id, name.clone(), score){
+                    Ok(msg)=>{
+                        if let Some(rematch) = self.rematches.get_mut(&old_id){
+                            rematch.place(name, msg);
+                        }
+                    }
+                    Err(e)=>println!("cant seat {} for the rematch {}",name,e),
+                }
+            }
+        }
+
+        self.rematches.retain(|_, r| !r.is_done(now));
+    }
+
+    pub fn join_queue(&mut self, username: String, score: i32)->Result<GameMessage,String>{
+        //someone who is in a game already is sent back there
+        if self.current_game(&username).is_some(){
+            return self.resume(&username)
+        }
+        if self.parties.party_of(&username).is_some(){
+            return Err("you are in a party, the leader queues for all of you".to_string())
+        }
+        let now = self.clock.now();
+        let ticket = self.queue.join(username, score, now);
+        self.queue.status(ticket, now)
+    }
+
+    //members of the party this user leads, the leader first
+    pub fn party_members(&self, leader: &str)->Result<Vec<String>,String>{
+        match self.parties.party_of(leader){
+            Some(party) if party.leader == leader=>Ok(party.members.clone()),
+            Some(_)=>Err("only the party leader can queue".to_string()),
+            None=>Err("not in a party".to_string()),
+        }
+    }
+
+    //the leader queues the whole party as one
+    pub fn queue_party(&mut self, leader: String, members: Vec<(String, i32)>)->Result<GameMessage,String>{
+        let party = self.parties.party_of(&leader).ok_or("not in a party".to_string())?;
+        let current : Vec<&String> = party.members.iter().collect();
+        if party.leader != leader || current.len() != members.len() || members.iter().any(|(n, _)| !current.contains(&n)){
+            return Err("the party changed, try again".to_string())
+        }
+        let party_id = party.id;
+        for (name, _) in members.iter(){
+            self.already_playing(name)?;
+        }
+
+        let now = self.clock.now();
+        let ticket = self.queue.join_group(members, Some(party_id), now);
+        self.queue.status(ticket, now)
+    }
+
+    pub fn create_party(&mut self, username: String)->Result<GameMessage,String>{
+        self.parties.create(username.clone())?;
+        self.parties.status(&username)
+    }
+
+    pub fn invite_to_party(&mut self, username: &str, friend: String)->Result<GameMessage,String>{
+        self.parties.invite(username, friend)?;
+        self.parties.status(username)
+    }
+
+    //whenever the party changes it has to queue again
+    fn party_changed(&mut self, leader: &str){
+        self.queue.remove_name(leader);
+    }
+
+    pub fn accept_party(&mut self, username: String, party_id: u32)->Result<GameMessage,String>{
+        self.parties.accept(username.clone(), party_id)?;
+        if let Some(leader) = self.parties.party_of(&username).map(|p| p.leader.clone()){
+            self.party_changed(&leader);
+        }
+        self.parties.status(&username)
+    }
+
+    pub fn leave_party(&mut self, username: &str)->Result<GameMessage,String>{
+        let leader = self.parties.party_of(username).map(|p| p.leader.clone());
+        self.parties.leave(username)?;
+        if let Some(leader) = leader{
+            self.party_changed(&leader);
+        }
+        self.parties.status(username)
+    }
+
+    pub fn promote_in_party(&mut self, username: &str, member: &str)->Result<GameMessage,String>{
+        self.parties.promote(username, member)?;
+        self.party_changed(username);
+        self.parties.status(username)
+    }
+
+    pub fn party_status(&mut self, username: &str)->Result<GameMessage,String>{
+        self.parties.status(username)
+    }
+
+    pub fn queue_status(&mut self, ticket: u32)->Result<GameMessage,String>{
+        self.queue.status(ticket, self.clock.now())
+    }
+
+    pub fn leave_queue(&mut self, ticket: u32)->Result<(),String>{
+        self.queue.leave(ticket)
+    }
+
+    //put the groups the queue came up with into fresh lobbies of their own
+    fn match_queue(&mut self){
+        let groups = self.queue.take_groups(self.clock.now());
+
+        for group in groups{
+            let id = self.add_game();
+            if let Some(game) = self.running_games.get_mut(&id){
+                game.queued = true;
+            }
+            println!("queue formed lobby {} with {} players",id,group.len());
+
+            for entry in group{
+                for (name, score) in entry.members{
+                    match self.seat(id, name.clone(), score){
+                        Ok(msg)=>{
+                            if let (Some(party), Some(game)) = (entry.party, self.running_games.get_mut(&id)){
+                                game.set_party(&name, party);
+                            }
+                            //the leader finds their seat through the ticket, the others through the party
+                            if name == entry.name{
+                                self.queue.place(entry.ticket, msg);
+                            }else{
+                                self.parties.place(name, msg);
+                            }
+                        }
+                        Err(e)=>println!("cant place {} from the queue {}",name,e),
+                    }
+                }
+            }
+        }
+    }
+
+    //quick play, kept apart from the queue on purpose: the next open lobby whatever the score, only the queue matches by rating
     pub fn get_game(&mut self, username : String, score: i32) -> Result<GameMessage,String>{
 
+        //someone who is in a game already is sent back there
+        if self.current_game(&username).is_some(){
+            return self.resume(&username)
+        }
+
         let g = self.running_games.get_mut(&self.open_game_id);
 
         if let Some(game) = g{
             if game.is_open(){
-                return game.add_player(username,score)
+                return self.seat(self.open_game_id, username, score)
             }
         }
 
         self.create_new_game();
 
-        self.running_games.get_mut(&self.open_game_id).expect("cant get created game").add_player(username,score)
+        self.seat(self.open_game_id, username, score)
 
     }
 
@@ -55,7 +476,7 @@ impl MatchMaker {
 
         match self.running_games.get_mut(&game_id){
             Some (game)=>{
-                if game.is_open(){
+                if game.in_lobby(){
                     return game.remove_player_from_lobby(&token)
                 }else{
                     return Err(NotFound("cant leave lobby, game allready started".to_string()))
@@ -91,6 +512,13 @@ impl MatchMaker {
         
     }   
 
+    pub fn set_ready(&mut self, game_id: i32, token: u32, ready: bool)->Result<(),String>{
+        match self.running_games.get_mut(&game_id){
+            Some(game)=>game.set_ready(token, ready),
+            None=>Err("cant get game".to_owned()),
+        }
+    }
+
     pub fn make_move(&mut self, game_id: i32, token:u32 ,start:i32, end:i32,spawn:i32)->MoveResult{
 
         let game = self.running_games.get_mut(&game_id);
@@ -105,8 +533,114 @@ impl MatchMaker {
         }
     }
 
+    pub fn queue_move(&mut self, game_id: i32, token:u32, start:i32, end:i32, spawn:i32, min_energy:f32)->Option<i32>{
+        self.running_games.get_mut(&game_id)?.queue_move(token, start, end, spawn, min_energy)
+    }
+
+    pub fn cancel_queued(&mut self, game_id: i32, token:u32, id:i32)->MoveResult{
+        match self.running_games.get_mut(&game_id){
+            Some(game)=>game.cancel_queued(token, id),
+            None=>MoveResult::Fail,
+        }
+    }
+
+    pub fn reorder_queued(&mut self, game_id: i32, token:u32, id:i32, index:usize)->MoveResult{
+        match self.running_games.get_mut(&game_id){
+            Some(game)=>game.reorder_queued(token, id, index),
+            None=>MoveResult::Fail,
+        }
+    }
+
+    pub fn end_turn(&mut self, game_id: i32, token:u32)->MoveResult{
+        match self.running_games.get_mut(&game_id){
+            Some(game)=>game.end_turn(token),
+            None=>MoveResult::Fail,
+        }
+    }
+
+    pub fn drop_treasure(&mut self, game_id: i32, token:u32, target:i32)->MoveResult{
+        match self.running_games.get_mut(&game_id){
+            Some(game)=>game.drop_treasure(token, target),
+            None=>MoveResult::Fail,
+        }
+    }
+
+    //advance all running games by one tick, returns the ids of games that just ended
+    pub fn tick_games(&mut self, dt: f32)->Vec<i32>{
+        self.match_queue();
+        self.form_rematches();
+
+        let mut ended = vec![];
+        for (id, game) in self.running_games.iter_mut(){
+            if game.tick(dt){
+                ended.push(*id);
+            }
+        }
+        ended
+    }
+
+    pub fn take_settlements(&mut self, game_id:i32)->Vec<(String,i32)>{
+        match self.running_games.get_mut(&game_id){
+            Some(game)=>game.take_settlements(),
+            None=>vec![],
+        }
+    }
+
+    //settlements of every game, for eliminations that happen between requests
+    pub fn take_all_settlements(&mut self)->Vec<(String,i32)>{
+        self.running_games.values_mut().flat_map(|game| game.take_settlements()).collect()
+    }
+
+    //drop lobbies nobody is in anymore and hand back the games that need settling
+    pub fn reap(&mut self, config: &ReaperConfig)->Vec<i32>{
+
+        let mut dropped = vec![];
+        let mut to_settle = vec![];
+
+        for (id, game) in self.running_games.iter_mut(){
+            let idle = game.idle_for();
+
+            if game.in_lobby(){
+                //the empty public lobby is where the next player goes, no need to drop it
+                let waiting_for_players = *id == self.open_game_id && game.is_empty();
+                if idle >= config.lobby_timeout && !waiting_for_players{
+                    game.close_lobby();
+                    dropped.push(*id);
+                }
+            }else if game.is_running(){
+                if idle >= config.game_timeout{
+                    game.abandon();
+                    to_settle.push(*id);
+                }
+            }else if game.finished_for().unwrap_or(f32::MAX) >= config.finished_timeout{
+                to_settle.push(*id);
+            }
+        }
+
+        for id in dropped{
+            println!("dropping idle lobby {}",id);
+            self.take_game(id);
+        }
+
+        let now = self.clock.now();
+        self.concluded.retain(|_, c| now.saturating_duration_since(c.at).as_secs_f32() < config.finished_timeout);
+
+        to_settle
+    }
+
     pub fn take_game(&mut self, game_id:i32)->Option<Game>{
         let g = self.running_games.remove(&game_id);
+        if let Some(code) = g.as_ref().and_then(|game| game.code.as_ref()){
+            self.private_lobbies.remove(code);
+        }
+        //finished games leave a rematch offer behind
+        if let Some(game) = g.as_ref().filter(|game| game.is_finished() && game.roster().len() as i32 >= MINPLAYERCOUNT){
+            let rematch = Rematch::new(game.roster().to_vec(), game.rules().clone(), self.clock.now());
+            self.rematches.insert(game_id, rematch);
+        }
+        if let Some((game, msg)) = g.as_ref().and_then(|game| Some((game, game.conclusion()?.clone()))){
+            self.concluded.insert(game_id, Concluded { msg, tokens: game.tokens(), at: self.clock.now() });
+        }
         g
     }
 
@@ -117,7 +651,18 @@ impl MatchMaker {
                 Ok(game.request_update(player_token))
             }
             None=>{
-                Err(NotFound("cant get game".to_owned()))
+                //the game is gone, its players still get to hear how it ended once
+                let concluded = self.concluded.get_mut(&_game_id).ok_or(NotFound("cant get game".to_owned()))?;
+                let before = concluded.tokens.len();
+                concluded.tokens.retain(|t| *t != player_token);
+                if concluded.tokens.len() == before{
+                    return Err(NotFound("cant get game".to_owned()))
+                }
+                let msg = concluded.msg.clone();
+                if concluded.tokens.is_empty(){
+                    self.concluded.remove(&_game_id);
+                }
+                Ok(Ok(Some(msg)))
             }
         }
     }
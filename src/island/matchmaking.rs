@@ -0,0 +1,283 @@
+use std::collections::{HashMap, VecDeque};
+use std::time;
+
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::GameMessage;
+use super::game::{MAXPLAYERCOUNT, MINPLAYERCOUNT};
+
+//how many of the last waits go into the estimate
+const WAIT_SAMPLES : usize = 20;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig{
+    //players per lobby the queue tries to fill
+    pub lobby_size: i32,
+    //rating difference players accept right away
+    pub rating_band: f32,
+    //how much the band widens per second of waiting
+    pub band_growth: f32,
+    //after this many seconds smaller lobbies are good enough
+    pub max_wait: f32,
+}
+
+impl Default for QueueConfig{
+    fn default()->Self{
+        QueueConfig{
+            lobby_size: 4,
+            rating_band: 100.,
+            band_growth: 10.,
+            max_wait: 120.,
+        }
+    }
+}
+
+impl QueueConfig{
+    fn lobby_size(&self)->usize{
+        self.lobby_size.clamp(MINPLAYERCOUNT, MAXPLAYERCOUNT) as usize
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct QueueEntry{
+    pub ticket: u32,
+    //the player who queued, the leader for parties
+    pub name: String,
+    //the average for parties
+    pub score: i32,
+    //everyone that comes along, the player who queued first
+    pub members: Vec<(String, i32)>,
+    pub party: Option<u32>,
+    since: time::Instant,
+}
+
+impl QueueEntry{
+    fn size(&self)->usize{
+        self.members.len()
+    }
+
+    fn waited(&self, now: time::Instant)->f32{
+        now.saturating_duration_since(self.since).as_secs_f32()
+    }
+
+    fn band(&self, now: time::Instant, config: &QueueConfig)->f32{
+        config.rating_band + config.band_growth * self.waited(now)
+    }
+}
+
+//players waiting for a lobby of players with a similar score
+pub struct RatingQueue{
+    waiting: Vec<QueueEntry>,
+    //the join message of players that got a lobby, until they come for it
+    placed: HashMap<u32, GameMessage>,
+    waits: VecDeque<f32>,
+    pub config: QueueConfig,
+}
+
+impl RatingQueue{
+
+    pub fn new(config: QueueConfig)->Self{
+        RatingQueue{
+            waiting: vec![],
+            placed: HashMap::new(),
+            waits: VecDeque::new(),
+            config,
+        }
+    }
+
+    //returns the ticket of the player, the old one if they are queued already
+    pub fn join(&mut self, name: String, score: i32, now: time::Instant)->u32{
+        self.join_group(vec![(name, score)], None, now)
+    }
+
+    //a party takes one spot in the queue and is placed as a whole
+    pub fn join_group(&mut self, members: Vec<(String, i32)>, party: Option<u32>, now: time::Instant)->u32{
+
+        let name = members[0].0.clone();
+        if let Some(entry) = self.waiting.iter().find(|e| e.name == name){
+            return entry.ticket
+        }
+        let score = members.iter().map(|(_, score)| score).sum::<i32>() / members.len() as i32;
+
+        let mut rng = rand::thread_rng();
+        let mut ticket = rng.gen::<u32>();
+        while self.placed.contains_key(&ticket) || self.waiting.iter().any(|e| e.ticket == ticket){
+            ticket = rng.gen::<u32>();
+        }
+
+        self.waiting.push(QueueEntry { ticket, name, score, members, party, since: now });
+        ticket
+    }
+
+    pub fn leave(&mut self, ticket: u32)->Result<(),String>{
+        let before = self.waiting.len();
+        self.waiting.retain(|e| e.ticket != ticket);
+        if self.waiting.len() == before{
+            return Err("not in the queue".to_string())
+        }
+        Ok(())
+    }
+
+    //the player got into a game some other way
+    pub fn remove_name(&mut self, name: &str){
+        self.waiting.retain(|e| e.members.iter().all(|(n, _)| n != name));
+    }
+
+    //where the player stands, or their join message once they got a lobby
+    pub fn status(&mut self, ticket: u32, now: time::Instant)->Result<GameMessage,String>{
+
+        if let Some(msg) = self.placed.remove(&ticket){
+            return Ok(msg)
+        }
+
+        let position = self.waiting.iter().position(|e| e.ticket == ticket).ok_or("not in the queue".to_string())?;
+        let waited = self.waiting[position].waited(now);
+
+        let estimated_wait = if self.waits.is_empty(){
+            -1.
+        }else{
+            let average = self.waits.iter().sum::<f32>() / self.waits.len() as f32;
+            f32::max(average - waited, 0.)
+        };
+
+        Ok(GameMessage::Queued {
+            ticket,
+            position: position as i32,
+            waited,
+            estimated_wait,
+        })
+    }
+
+    pub fn place(&mut self, ticket: u32, msg: GameMessage){
+        self.placed.insert(ticket, msg);
+    }
+
+    //take out groups of players that fit together, longest waiting players get served first
+    pub fn take_groups(&mut self, now: time::Instant)->Vec<Vec<QueueEntry>>{
+
+        let size = self.config.lobby_size();
+        let mut groups = vec![];
+        let mut i = 0;
+
+        while i < self.waiting.len(){
+
+            let first = &self.waiting[i];
+            let band = first.band(now, &self.config);
+            //a party bigger than the usual lobby gets a lobby of its own size
+            let size = size.max(first.size());
+
+            //everyone who accepts the first player and is accepted in return, closest score first
+            let mut candidates : Vec<usize> = (0..self.waiting.len())
+                .filter(|j| *j != i)
+                .filter(|j|{
+                    let other = &self.waiting[*j];
+                    let diff = (other.score - first.score).abs() as f32;
+                    diff <= band && diff <= other.band(now, &self.config)
+                })
+                .collect();
+            candidates.sort_by_key(|j| (self.waiting[*j].score - first.score).abs());
+
+            //parties only come in whole
+            let mut count = first.size();
+            let mut fitting = vec![];
+            for j in candidates{
+                if count + self.waiting[j].size() <= size{
+                    count += self.waiting[j].size();
+                    fitting.push(j);
+                }
+            }
+
+            let patient = first.waited(now) >= self.config.max_wait;
+            let enough = count >= size || (patient && count >= MINPLAYERCOUNT as usize);
+            if !enough{
+                i += 1;
+                continue
+            }
+
+            fitting.push(i);
+            fitting.sort_unstable_by(|a, b| b.cmp(a));
+            let mut group = vec![];
+            for j in fitting{
+                group.push(self.waiting.remove(j));
+            }
+            group.reverse();
+
+            for entry in group.iter(){
+                self.waits.push_back(entry.waited(now));
+                if self.waits.len() > WAIT_SAMPLES{
+                    self.waits.pop_front();
+                }
+            }
+            groups.push(group);
+
+            //removed entries before i shift the rest down, start over from the oldest
+            i = 0;
+        }
+        groups
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn queue()->RatingQueue{
+        RatingQueue::new(QueueConfig { lobby_size: 2, rating_band: 100., band_growth: 10., max_wait: 120. })
+    }
+
+    fn names(group: &[QueueEntry])->Vec<String>{
+        group.iter().map(|e| e.name.clone()).collect()
+    }
+
+    #[test]
+    fn close_scores_are_grouped_right_away(){
+        let mut queue = queue();
+        let now = time::Instant::now();
+        queue.join("a".to_string(), 1000, now);
+        queue.join("b".to_string(), 1050, now);
+
+        let groups = queue.take_groups(now);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(names(&groups[0]), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn band_widens_with_waiting(){
+        let mut queue = queue();
+        let now = time::Instant::now();
+        queue.join("a".to_string(), 1000, now);
+        queue.join("b".to_string(), 1300, now);
+
+        assert!(queue.take_groups(now).is_empty());
+        assert!(queue.take_groups(now + time::Duration::from_secs(19)).is_empty());
+        assert_eq!(queue.take_groups(now + time::Duration::from_secs(21)).len(), 1);
+    }
+
+    #[test]
+    fn both_sides_have_to_accept(){
+        let mut queue = queue();
+        let now = time::Instant::now();
+        //the old player would take anyone by now, the newcomer is still picky
+        queue.join("a".to_string(), 1000, now);
+        let later = now + time::Duration::from_secs(60);
+        queue.join("b".to_string(), 1300, later);
+
+        assert!(queue.take_groups(later).is_empty());
+        assert_eq!(queue.take_groups(later + time::Duration::from_secs(21)).len(), 1);
+    }
+
+    #[test]
+    fn closest_score_is_picked_first(){
+        let mut queue = queue();
+        let now = time::Instant::now();
+        queue.join("a".to_string(), 1000, now);
+        queue.join("far".to_string(), 1090, now);
+        queue.join("near".to_string(), 1010, now);
+
+        let groups = queue.take_groups(now);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(names(&groups[0]), vec!["a", "near"]);
+    }
+}
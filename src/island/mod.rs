@@ -1,2 +1,10 @@
+pub mod clock;
 pub mod handler;
-pub mod game;
\ No newline at end of file
+pub mod matchmaking;
+pub mod parties;
+pub mod game;
+pub mod pieces;
+pub mod reaper;
+pub mod rematch;
+pub mod rules;
+pub mod spectators;
\ No newline at end of file
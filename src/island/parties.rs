@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use crate::GameMessage;
+use super::game::MAXPLAYERCOUNT;
+
+//friends that queue together, the leader speaks for all of them
+pub struct Party{
+    pub id: u32,
+    pub leader: String,
+    //the leader is always the first member
+    pub members: Vec<String>,
+    invited: Vec<String>,
+}
+
+pub struct Parties{
+    parties: HashMap<u32, Party>,
+    member_of: HashMap<String, u32>,
+    //join messages for members the leader got into a game
+    placed: HashMap<String, GameMessage>,
+}
+
+impl Parties{
+
+    pub fn new()->Self{
+        Parties{
+            parties: HashMap::new(),
+            member_of: HashMap::new(),
+            placed: HashMap::new(),
+        }
+    }
+
+    pub fn party_of(&self, name: &str)->Option<&Party>{
+        self.parties.get(self.member_of.get(name)?)
+    }
+
+    pub fn create(&mut self, leader: String)->Result<u32,String>{
+        if self.member_of.contains_key(&leader){
+            return Err("already in a party".to_string())
+        }
+        let mut rng = rand::thread_rng();
+        let mut id = rng.gen::<u32>();
+        while self.parties.contains_key(&id){
+            id = rng.gen::<u32>();
+        }
+        self.member_of.insert(leader.clone(), id);
+        self.parties.insert(id, Party { id, leader: leader.clone(), members: vec![leader], invited: vec![] });
+        Ok(id)
+    }
+
+    fn led_by(&mut self, leader: &str)->Result<&mut Party,String>{
+        let id = *self.member_of.get(leader).ok_or("not in a party".to_string())?;
+        match self.parties.get_mut(&id){
+            Some(party) if party.leader == leader=>Ok(party),
+            _=>Err("only the party leader can do that".to_string()),
+        }
+    }
+
+    pub fn invite(&mut self, leader: &str, friend: String)->Result<u32,String>{
+        let party = self.led_by(leader)?;
+        if party.members.contains(&friend){
+            return Err("already in the party".to_string())
+        }
+        if !party.invited.contains(&friend){
+            party.invited.push(friend);
+        }
+        Ok(party.id)
+    }
+
+    pub fn accept(&mut self, name: String, party_id: u32)->Result<u32,String>{
+        if self.member_of.contains_key(&name){
+            return Err("already in a party".to_string())
+        }
+        let party = self.parties.get_mut(&party_id).ok_or("cant find this party".to_string())?;
+        if !party.invited.contains(&name){
+            return Err("not invited to this party".to_string())
+        }
+        //the whole party has to fit into one game
+        if party.members.len() as i32 >= MAXPLAYERCOUNT{
+            return Err("the party is full".to_string())
+        }
+        party.invited.retain(|n| *n != name);
+        party.members.push(name.clone());
+        self.member_of.insert(name, party_id);
+        Ok(party_id)
+    }
+
+    //a leaving leader hands the party to the next member, an empty party is gone
+    pub fn leave(&mut self, name: &str)->Result<u32,String>{
+        let id = self.member_of.remove(name).ok_or("not in a party".to_string())?;
+        if let Some(party) = self.parties.get_mut(&id){
+            party.members.retain(|n| n != name);
+            if party.members.is_empty(){
+                self.parties.remove(&id);
+            }else if party.leader == name{
+                party.leader = party.members[0].clone();
+            }
+        }
+        Ok(id)
+    }
+
+    pub fn promote(&mut self, leader: &str, member: &str)->Result<u32,String>{
+        let party = self.led_by(leader)?;
+        let at = party.members.iter().position(|n| n == member).ok_or("not in the party".to_string())?;
+        let new_leader = party.members.remove(at);
+        party.members.insert(0, new_leader.clone());
+        party.leader = new_leader;
+        Ok(party.id)
+    }
+
+    pub fn place(&mut self, name: String, msg: GameMessage){
+        self.placed.insert(name, msg);
+    }
+
+    //the party as it is, or the join message once the leader got everyone into a game
+    pub fn status(&mut self, name: &str)->Result<GameMessage,String>{
+        if let Some(msg) = self.placed.remove(name){
+            return Ok(msg)
+        }
+        let invites : Vec<u32> = self.parties.values().filter(|p| p.invited.iter().any(|n| n == name)).map(|p| p.id).collect();
+        match self.party_of(name){
+            Some(party)=>Ok(GameMessage::Party {
+                party_id: party.id,
+                leader: party.leader.clone(),
+                members: party.members.clone(),
+                invited: party.invited.clone(),
+                invites,
+            }),
+            None=>Ok(GameMessage::Party {
+                party_id: 0,
+                leader: String::new(),
+                members: vec![],
+                invited: vec![],
+                invites,
+            }),
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use serde::{Deserialize, Serialize, Serializer};
+
+pub const DIAGONALS :[(i32, i32);4] = [(1,1),(-1,1),(1,-1),(-1,-1)];
+pub const STRAIGHTS : [(i32,i32);4] = [(1,0),(-1,0),(0,1),(0,-1)];
+pub const KNIGHTHOPS : [(i32,i32);8] = [(1,2),(2,1),(1,-2),(2,-1),(-1,-2),(-2,-1),(-1,2),(-2,1)];
+const VIEW_SIZE : i32 = 9 ;
+const VIEW_RADIUS: i32 =  VIEW_SIZE/2;
+//seconds a piece has to wait after acting unless it says otherwise
+const MOVE_DELAY: f32 = 0.15;
+//longest cooldown a configured piece can have, in seconds
+const MAX_COOLDOWN: f32 = 3600.;
+
+//how a piece gets from one tile to another, directions are given for a piece looking along +x
+pub trait MovementRule: Send + Sync{
+    //`open` tells if a tile on the way can be passed, the target tile itself is checked by the game
+    fn reaches(&self, start:(i32,i32), end:(i32,i32), forward:(i32,i32), open:&dyn Fn(i32,i32)->bool)->bool;
+}
+
+//turn a direction so that +x points along forward
+fn rotate(dir:(i32,i32), forward:(i32,i32))->(i32,i32){
+    (dir.0 * forward.0 - dir.1 * forward.1, dir.0 * forward.1 + dir.1 * forward.0)
+}
+
+//one tile in any of the directions
+pub struct Stepper(pub Vec<(i32,i32)>);
+
+//any number of tiles along a direction until something is in the way, range 0 is unlimited
+pub struct Slider{
+    pub dirs: Vec<(i32,i32)>,
+    pub range: i32,
+}
+
+//jumps straight to the offset, no matter what stands between
+pub struct Leaper(pub Vec<(i32,i32)>);
+
+//can do whatever any of its parts can
+pub struct Combination(pub Vec<Box<dyn MovementRule>>);
+
+impl MovementRule for Stepper{
+    fn reaches(&self, start:(i32,i32), end:(i32,i32), forward:(i32,i32), _open:&dyn Fn(i32,i32)->bool)->bool{
+        self.0.iter().any(|dir|{
+            let (dx, dy) = rotate(*dir, forward);
+            (start.0 + dx, start.1 + dy) == end
+        })
+    }
+}
+
+impl MovementRule for Leaper{
+    fn reaches(&self, start:(i32,i32), end:(i32,i32), forward:(i32,i32), _open:&dyn Fn(i32,i32)->bool)->bool{
+        self.0.iter().any(|hop|{
+            let (dx, dy) = rotate(*hop, forward);
+            (start.0 + dx, start.1 + dy) == end
+        })
+    }
+}
+
+impl MovementRule for Slider{
+    fn reaches(&self, start:(i32,i32), end:(i32,i32), forward:(i32,i32), open:&dyn Fn(i32,i32)->bool)->bool{
+        for dir in self.dirs.iter(){
+            let (dx, dy) = rotate(*dir, forward);
+            let mut target = start;
+            let mut steps = 0;
+            loop{
+                target = (target.0 + dx, target.1 + dy);
+                steps += 1;
+                if target == end{
+                    return true
+                }
+                if !open(target.0, target.1) || (self.range > 0 && steps >= self.range){
+                    break
+                }
+            }
+        }
+        false
+    }
+}
+
+impl MovementRule for Combination{
+    fn reaches(&self, start:(i32,i32), end:(i32,i32), forward:(i32,i32), open:&dyn Fn(i32,i32)->bool)->bool{
+        self.0.iter().any(|rule| rule.reaches(start, end, forward, open))
+    }
+}
+
+//movement as it is written in the config
+#[derive(Clone, Debug, Deserialize)]
+pub enum MoveSpec{
+    Step(Vec<(i32,i32)>),
+    Slide{ dirs: Vec<(i32,i32)>, #[serde(default)] range: i32 },
+    Leap(Vec<(i32,i32)>),
+}
+
+impl MoveSpec{
+    fn build(specs: &[MoveSpec])->Box<dyn MovementRule>{
+        let mut rules : Vec<Box<dyn MovementRule>> = vec![];
+        for spec in specs{
+            rules.push(match spec{
+                MoveSpec::Step(dirs)=>Box::new(Stepper(dirs.clone())),
+                MoveSpec::Slide { dirs, range }=>Box::new(Slider{ dirs: dirs.clone(), range: *range }),
+                MoveSpec::Leap(hops)=>Box::new(Leaper(hops.clone())),
+            });
+        }
+        if rules.len() == 1{
+            rules.pop().unwrap()
+        }else{
+            Box::new(Combination(rules))
+        }
+    }
+}
+
+//what kind of tiles a piece can stand on
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub enum Terrain{
+    #[default]
+    Land,
+    Water,
+}
+
+//a piece type as read from the [[pieces]] tables of rocket.toml
+#[derive(Clone, Debug, Deserialize)]
+pub struct PieceConfig{
+    pub name: String,
+    //number the client uses to spawn it
+    pub num: u8,
+    pub cost: u32,
+    pub sight: i32,
+    pub moves: Vec<MoveSpec>,
+    //leave out to capture the same way the piece moves, empty for a piece that cant capture
+    pub captures: Option<Vec<MoveSpec>>,
+    //seconds before it can act again
+    pub cooldown: Option<f32>,
+    //directions are relative to the owners forward direction
+    #[serde(default)]
+    pub directional: bool,
+    //takes pieces from where it stands instead of moving onto their tile
+    #[serde(default)]
+    pub ranged: bool,
+    #[serde(default)]
+    pub terrain: Terrain,
+}
+
+pub struct PieceDef{
+    pub name: String,
+    pub cost: u32,
+    pub sight: i32,
+    pub cooldown: f32,
+    //the king comes with the player and cant be bought
+    pub spawnable: bool,
+    pub directional: bool,
+    pub ranged: bool,
+    pub terrain: Terrain,
+    pub moves: Box<dyn MovementRule>,
+    pub captures: Option<Box<dyn MovementRule>>,
+}
+
+impl PieceDef{
+    fn new(name:&str, cost:u32, sight:i32, moves: Box<dyn MovementRule>, captures: Option<Box<dyn MovementRule>>)->PieceDef{
+        PieceDef{
+            name: name.to_string(),
+            cost,
+            sight,
+            cooldown: MOVE_DELAY,
+            spawnable: true,
+            directional: false,
+            ranged: false,
+            terrain: Terrain::Land,
+            moves,
+            captures,
+        }
+    }
+
+    fn from_config(config: &PieceConfig)->PieceDef{
+        let captures = match &config.captures{
+            None=>Some(MoveSpec::build(&config.moves)),
+            Some(specs) if specs.is_empty()=>None,
+            Some(specs)=>Some(MoveSpec::build(specs)),
+        };
+        PieceDef{
+            name: config.name.clone(),
+            cost: config.cost,
+            sight: config.sight,
+            //a broken value in the config would make the move that starts the cooldown panic
+            cooldown: config.cooldown.filter(|c| c.is_finite()).unwrap_or(MOVE_DELAY).clamp(0., MAX_COOLDOWN),
+            spawnable: true,
+            directional: config.directional,
+            ranged: config.ranged,
+            terrain: config.terrain,
+            moves: MoveSpec::build(&config.moves),
+            captures,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PieceInfo{
+    pub num: u8,
+    pub name: String,
+    pub cost: u32,
+    pub sight: i32,
+    pub cooldown: f32,
+    pub spawnable: bool,
+    pub can_capture: bool,
+    pub ranged: bool,
+    pub terrain: Terrain,
+}
+
+pub struct PieceRegistry{
+    defs: HashMap<u8, PieceDef>,
+    //stands in for the pawn when the rules ask for directional pawns
+    directional_pawn: PieceDef,
+}
+
+fn queen_moves()->Box<dyn MovementRule>{
+    Box::new(Slider{ dirs: [STRAIGHTS, DIAGONALS].concat(), range: 0 })
+}
+
+impl PieceRegistry{
+
+    fn builtin()->PieceRegistry{
+
+        let all_around = [STRAIGHTS, DIAGONALS].concat();
+        let mut defs = HashMap::new();
+
+        let mut king = PieceDef::new("King", 0, VIEW_RADIUS, Box::new(Stepper(all_around.clone())), Some(Box::new(Stepper(all_around.clone()))));
+        king.spawnable = false;
+        defs.insert(Piece::KING.0, king);
+        let mut queen = PieceDef::new("Queen", 9, 2, queen_moves(), Some(queen_moves()));
+        queen.cooldown = 0.6;
+        defs.insert(Piece::QUEEN.0, queen);
+        defs.insert(Piece::BISHOP.0, PieceDef::new("Bishop", 3, 2,
+            Box::new(Slider{ dirs: DIAGONALS.to_vec(), range: 0 }),
+            Some(Box::new(Slider{ dirs: DIAGONALS.to_vec(), range: 0 }))));
+        defs.insert(Piece::KNIGHT.0, PieceDef::new("Knight", 3, 2,
+            Box::new(Leaper(KNIGHTHOPS.to_vec())),
+            Some(Box::new(Leaper(KNIGHTHOPS.to_vec())))));
+        let mut rook = PieceDef::new("Rook", 5, 2,
+            Box::new(Slider{ dirs: STRAIGHTS.to_vec(), range: 0 }),
+            Some(Box::new(Slider{ dirs: STRAIGHTS.to_vec(), range: 0 })));
+        rook.cooldown = 0.4;
+        defs.insert(Piece::ROOK.0, rook);
+        defs.insert(Piece::PAWN.0, PieceDef::new("Pawn", 1, 1,
+            Box::new(Stepper(STRAIGHTS.to_vec())),
+            Some(Box::new(Stepper(DIAGONALS.to_vec())))));
+
+        //shoots along straight lines without leaving its tile
+        let mut cannon = PieceDef::new("Cannon", 6, 3,
+            Box::new(Stepper(STRAIGHTS.to_vec())),
+            Some(Box::new(Slider{ dirs: STRAIGHTS.to_vec(), range: 4 })));
+        cannon.ranged = true;
+        cannon.cooldown = 1.5;
+        defs.insert(Piece::CANNON.0, cannon);
+
+        //sees far but cant take anything
+        defs.insert(Piece::SCOUT.0, PieceDef::new("Scout", 2, 6,
+            Box::new(Slider{ dirs: all_around.clone(), range: 2 }),
+            None));
+
+        let mut ship = PieceDef::new("Ship", 4, 3,
+            Box::new(Slider{ dirs: all_around.clone(), range: 3 }),
+            Some(Box::new(Slider{ dirs: all_around.clone(), range: 3 })));
+        ship.terrain = Terrain::Water;
+        defs.insert(Piece::SHIP.0, ship);
+
+        let mut directional_pawn = PieceDef::new("Pawn", 1, 1,
+            Box::new(Stepper(vec![(1,0)])),
+            Some(Box::new(Stepper(vec![(1,1),(1,-1)]))));
+        directional_pawn.directional = true;
+
+        PieceRegistry { defs, directional_pawn }
+    }
+
+    pub fn get(&self, piece: Piece)->&PieceDef{
+        self.defs.get(&piece.0).expect("piece missing from registry")
+    }
+
+    pub fn directional_pawn(&self)->&PieceDef{
+        &self.directional_pawn
+    }
+
+    //what the client needs to know about every piece it can use, water pieces only where there is water
+    pub fn catalogue(&self, water: bool)->Vec<PieceInfo>{
+        let mut res : Vec<PieceInfo> = self.defs.iter().filter(|(_, def)| water || def.terrain != Terrain::Water).map(|(num, def)| PieceInfo{
+            num: *num,
+            name: def.name.clone(),
+            cost: def.cost,
+            sight: def.sight,
+            cooldown: def.cooldown,
+            spawnable: def.spawnable,
+            can_capture: def.captures.is_some(),
+            ranged: def.ranged,
+            terrain: def.terrain,
+        }).collect();
+        res.sort_by_key(|info| info.num);
+        res
+    }
+}
+
+static REGISTRY : OnceLock<PieceRegistry> = OnceLock::new();
+
+//add the configured pieces to the built in ones, has to run before the first game starts
+pub fn init(configs: Vec<PieceConfig>){
+    let mut registry = PieceRegistry::builtin();
+    for config in configs.iter(){
+        if config.num == Piece::KING.0{
+            println!("cant replace the king with {}",config.name);
+            continue
+        }
+        println!("registering piece {} as {}",config.name,config.num);
+        if let Some(old) = registry.defs.insert(config.num, PieceDef::from_config(config)){
+            println!("piece {} replaces {} as {}",config.name,old.name,config.num);
+        }
+    }
+    if REGISTRY.set(registry).is_err(){
+        println!("piece registry was already set up");
+    }
+}
+
+pub fn registry()->&'static PieceRegistry{
+    REGISTRY.get_or_init(PieceRegistry::builtin)
+}
+
+//a piece type, the number is the one the client uses to spawn it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Piece(pub u8);
+
+impl Piece{
+    pub const KING : Piece = Piece(1);
+    pub const QUEEN : Piece = Piece(2);
+    pub const BISHOP : Piece = Piece(3);
+    pub const KNIGHT : Piece = Piece(4);
+    pub const ROOK : Piece = Piece(5);
+    pub const PAWN : Piece = Piece(6);
+    pub const CANNON : Piece = Piece(7);
+    pub const SCOUT : Piece = Piece(8);
+    pub const SHIP : Piece = Piece(9);
+
+    pub fn from_num(num:i32)->Option<Piece>{
+        let id = u8::try_from(num).ok()?;
+        if registry().defs.contains_key(&id){
+            Some(Piece(id))
+        }else{
+            None
+        }
+    }
+
+    pub fn def(&self)->&'static PieceDef{
+        registry().get(*self)
+    }
+
+    pub fn get_cost(&self)->u32{
+        self.def().cost
+    }
+
+    //how many tiles around itself the piece can see
+    pub fn get_sight(&self)->i32{
+        self.def().sight
+    }
+}
+
+//the client knows pieces by name
+impl Serialize for Piece{
+    fn serialize<S: Serializer>(&self, serializer: S)->Result<S::Ok, S::Error>{
+        serializer.serialize_str(&self.def().name)
+    }
+}
@@ -0,0 +1,36 @@
+use serde::Deserialize;
+
+//the reaper looks at least this often, whatever the config says
+const MAX_INTERVAL : f32 = 3600.;
+
+//when games nobody looks at anymore get cleaned up, all in seconds
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ReaperConfig{
+    //how often the reaper looks through the games
+    pub interval: f32,
+    //lobbies where nobody asked for an update
+    pub lobby_timeout: f32,
+    //running games where none of the players is polling anymore
+    pub game_timeout: f32,
+    //finished games that never got settled, and how long players can still fetch how a game ended
+    pub finished_timeout: f32,
+}
+
+impl Default for ReaperConfig{
+    fn default()->Self{
+        ReaperConfig{
+            interval: 5.,
+            lobby_timeout: 120.,
+            game_timeout: 120.,
+            finished_timeout: 30.,
+        }
+    }
+}
+
+impl ReaperConfig{
+    //seconds between rounds, never shorter than the given tick
+    pub fn interval(&self, tick: f32)->f32{
+        Some(self.interval).filter(|i| i.is_finite()).unwrap_or(ReaperConfig::default().interval).clamp(tick, MAX_INTERVAL)
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::time;
+
+use crate::GameMessage;
+use super::game::MINPLAYERCOUNT;
+use super::rules::GameRules;
+
+//offers are forgotten this many seconds after they ran out
+const REMATCH_GRACE : f32 = 60.;
+//longest a rematch offer stays open, whatever the config says
+const MAX_REMATCH_TIMEOUT : f32 = 3600.;
+
+//the offer to play again with the same people after a game ended
+pub struct Rematch{
+    pub rules: GameRules,
+    //everyone who played the game that ended
+    roster: Vec<String>,
+    //names and scores of those who said yes
+    accepted: Vec<(String, i32)>,
+    deadline: time::Instant,
+    //join messages for the new lobby, until each player comes for theirs
+    placed: HashMap<String, GameMessage>,
+    formed: bool,
+}
+
+impl Rematch{
+
+    pub fn new(roster: Vec<String>, rules: GameRules, now: time::Instant)->Self{
+        let timeout = Some(rules.rematch_timeout).filter(|t| t.is_finite()).unwrap_or(MAX_REMATCH_TIMEOUT).clamp(0., MAX_REMATCH_TIMEOUT);
+        let deadline = now + time::Duration::from_secs_f32(timeout);
+        Rematch{
+            rules,
+            roster,
+            accepted: vec![],
+            deadline,
+            placed: HashMap::new(),
+            formed: false,
+        }
+    }
+
+    pub fn accept(&mut self, name: String, score: i32, now: time::Instant)->Result<(),String>{
+        if !self.roster.contains(&name){
+            return Err("you did not play this game".to_string())
+        }
+        if self.formed || now > self.deadline{
+            return Err("the rematch offer is over".to_string())
+        }
+        if !self.accepted.iter().any(|(n, _)| *n == name){
+            self.accepted.push((name, score));
+        }
+        Ok(())
+    }
+
+    //everybody said yes, or time is up and there are enough for a game
+    pub fn ready(&self, now: time::Instant)->bool{
+        let everyone = self.accepted.len() == self.roster.len();
+        let enough = now > self.deadline && self.accepted.len() as i32 >= MINPLAYERCOUNT;
+        !self.formed && (everyone || enough)
+    }
+
+    //the players for the new lobby, the offer is closed after this
+    pub fn form(&mut self)->Vec<(String, i32)>{
+        self.formed = true;
+        self.accepted.clone()
+    }
+
+    pub fn place(&mut self, name: String, msg: GameMessage){
+        self.placed.insert(name, msg);
+    }
+
+    //nothing more will happen with this offer
+    pub fn is_done(&self, now: time::Instant)->bool{
+        let forgotten = now.saturating_duration_since(self.deadline).as_secs_f32() > REMATCH_GRACE;
+        (self.formed && self.placed.is_empty()) || forgotten
+    }
+
+    //who is in so far, or the join message once the new lobby is there
+    pub fn status(&mut self, game_id: i32, name: &str, now: time::Instant)->Result<GameMessage,String>{
+        if let Some(msg) = self.placed.remove(name){
+            return Ok(msg)
+        }
+        if !self.roster.iter().any(|n| n == name){
+            return Err("you did not play this game".to_string())
+        }
+        if self.formed || (now > self.deadline && !self.ready(now)){
+            return Err("the rematch offer is over".to_string())
+        }
+        Ok(GameMessage::Rematch {
+            game_id,
+            accepted: self.accepted.iter().map(|(n, _)| n.clone()).collect(),
+            roster: self.roster.clone(),
+            time_left: self.deadline.saturating_duration_since(now).as_secs_f32(),
+        })
+    }
+}
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+
+//what happens to the pieces of a player that leaves a running game
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AbandonRule{
+    //pieces are taken off the board
+    Remove,
+    //pieces turn into neutral obstacles that nobody can move or take
+    Obstacle,
+    //pieces stay where they are and anyone can capture them
+    Capturable,
+}
+
+//what happens once the match clock runs out
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SuddenDeath{
+    //energy regen drains to zero over the sudden death time
+    ShrinkingRegen,
+    //everybody sees where the treasures are
+    TreasureReveal,
+    //the game ends right away and the pot is split by material on the board
+    SplitPot,
+}
+
+//what happens to a player whose client stopped polling
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DisconnectRule{
+    //nothing, the player's pieces just stand there
+    Keep,
+    //the player's energy stops filling up until they are back
+    PauseEnergy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PawnVariant{
+    //one step in any straight direction, take diagonally in any direction
+    Classic,
+    //walk away from the starting side and promote near the treasure or at the far edge
+    Directional,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum GameMode{
+    Classic,
+    //the island floods from the outside in, the last king standing wins
+    ShrinkingIsland,
+}
+
+impl GameMode{
+    //only the flood brings water onto the board
+    pub fn has_water(&self)->bool{
+        *self == GameMode::ShrinkingIsland
+    }
+}
+
+//read from the [rules] table of rocket.toml, anything missing falls back to the default
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct GameRules{
+    pub mode: GameMode,
+    pub abandon: AbandonRule,
+    //the last player with a king left wins, next to carrying the treasure home
+    pub last_king_standing: bool,
+    //number of treasures on the island and how many a player has to bring to the harbour
+    pub treasure_count: usize,
+    pub treasures_to_win: i32,
+    //seconds until a banked or lost treasure shows up on its home tile again
+    pub treasure_respawn: f32,
+    //players may put down the treasure next to their king
+    pub voluntary_drop: bool,
+    //seconds until the clock runs out, 0 for no limit
+    pub match_duration: f32,
+    pub sudden_death: SuddenDeath,
+    //seconds of sudden death before the pot is split anyway
+    pub sudden_death_duration: f32,
+    //seconds until the outermost ring floods and between the following rings
+    pub flood_start: f32,
+    pub flood_interval: f32,
+    //players per team, 1 is free for all
+    pub team_size: i32,
+    pub pawns: PawnVariant,
+    //promotion costs the difference in piece cost unless this is set
+    pub free_promotion: bool,
+    //moves are handed in for a round and played at the same time
+    pub turn_based: bool,
+    pub actions_per_round: i32,
+    //seconds players get to hand in their moves
+    pub round_duration: f32,
+    //seconds between everyone being ready and the start
+    pub ready_countdown: f32,
+    //seconds without polling until a player counts as disconnected
+    pub disconnect_timeout: f32,
+    pub disconnect: DisconnectRule,
+    //whether people outside the game may watch it, and how many seconds late they see it
+    pub spectators: bool,
+    pub spectator_delay: f32,
+    //seconds players have to accept a rematch once the game is over
+    pub rematch_timeout: f32,
+}
+
+impl Default for GameRules{
+    fn default()->Self{
+        GameRules{
+            mode: GameMode::Classic,
+            abandon: AbandonRule::Capturable,
+            last_king_standing: true,
+            treasure_count: 1,
+            treasures_to_win: 1,
+            treasure_respawn: 30.,
+            voluntary_drop: true,
+            match_duration: 900.,
+            sudden_death: SuddenDeath::ShrinkingRegen,
+            sudden_death_duration: 120.,
+            flood_start: 120.,
+            flood_interval: 60.,
+            team_size: 1,
+            pawns: PawnVariant::Classic,
+            free_promotion: false,
+            turn_based: false,
+            actions_per_round: 3,
+            round_duration: 20.,
+            ready_countdown: 10.,
+            disconnect_timeout: 10.,
+            disconnect: DisconnectRule::PauseEnergy,
+            spectators: true,
+            spectator_delay: 30.,
+            rematch_timeout: 30.,
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::{HashMap, VecDeque};
+use std::time;
+
+use rand::Rng;
+use tokio::sync::oneshot;
+
+use crate::GameMessage;
+use super::game::Tile;
+
+const MAX_SPECTATORS : usize = 50;
+//spectators that stop polling for this many seconds are dropped
+const SPECTATOR_TIMEOUT : f32 = 60.;
+
+//the board as it was at some point, what spectators get to see later
+pub struct Snapshot{
+    pub at: time::Instant,
+    pub tiles: Vec<(i32, Tile)>,
+    //the tiles each player number had in sight
+    pub sight: HashMap<i32, Vec<i32>>,
+    pub players: Vec<(String, i32)>,
+}
+
+struct Spectator{
+    //player number whose view the spectator gets, -1 for the whole board
+    follow: i32,
+    update_sender: Option<oneshot::Sender<GameMessage>>,
+    //the frame the spectator got last
+    last_frame: Option<time::Instant>,
+    last_seen: time::Instant,
+}
+
+//people watching a game, they see it a while late so they cant tell players what is going on
+pub struct Spectators{
+    watchers: HashMap<u32, Spectator>,
+    history: VecDeque<Snapshot>,
+    delay: f32,
+}
+
+impl Spectators{
+
+    pub fn new(delay: f32)->Self{
+        Spectators{
+            watchers: HashMap::new(),
+            history: VecDeque::new(),
+            delay: delay.max(0.),
+        }
+    }
+
+    pub fn count(&self)->i32{
+        self.watchers.len() as i32
+    }
+
+    pub fn add(&mut self, follow: i32, now: time::Instant)->Option<u32>{
+        if self.watchers.len() >= MAX_SPECTATORS{
+            return None
+        }
+        let mut rng = rand::thread_rng();
+        let mut token = rng.gen::<u32>();
+        while self.watchers.contains_key(&token){
+            token = rng.gen::<u32>();
+        }
+        self.watchers.insert(token, Spectator { follow, update_sender: None, last_frame: None, last_seen: now });
+        Some(token)
+    }
+
+    pub fn remove(&mut self, token: u32)->bool{
+        self.watchers.remove(&token).is_some()
+    }
+
+    pub fn follow(&mut self, token: u32, follow: i32)->bool{
+        match self.watchers.get_mut(&token){
+            Some(spectator)=>{
+                spectator.follow = follow;
+                //the next poll gets the frame again from the new view
+                spectator.last_frame = None;
+                true
+            }
+            None=>false,
+        }
+    }
+
+    //the newest snapshot that is old enough to be shown
+    fn frame(&self, now: time::Instant)->Option<&Snapshot>{
+        self.history.iter().rev().find(|snap| now.saturating_duration_since(snap.at).as_secs_f32() >= self.delay)
+    }
+
+    fn view(&self, snap: &Snapshot, game_id: i32, follow: i32)->GameMessage{
+        let data = match snap.sight.get(&follow){
+            Some(sight)=>snap.tiles.iter().filter(|(n, _)| sight.contains(n)).cloned().collect(),
+            None=>snap.tiles.clone(),
+        };
+        GameMessage::Spectate {
+            game_id,
+            data,
+            follow,
+            players: snap.players.clone(),
+            delay: self.delay,
+            spectators: self.count(),
+        }
+    }
+
+    pub fn record(&mut self, snap: Snapshot, game_id: i32, now: time::Instant){
+        self.history.push_back(snap);
+
+        //only the frame on screen right now and the ones after it are needed
+        while self.history.len() > 1 && now.saturating_duration_since(self.history[1].at).as_secs_f32() >= self.delay{
+            self.history.pop_front();
+        }
+
+        self.watchers.retain(|_, s| now.saturating_duration_since(s.last_seen).as_secs_f32() < SPECTATOR_TIMEOUT);
+
+        let frame = match self.frame(now){
+            Some(frame)=>frame,
+            None=>return,
+        };
+        let mut sent = vec![];
+        for (token, spectator) in self.watchers.iter(){
+            if spectator.update_sender.is_some() && spectator.last_frame != Some(frame.at){
+                sent.push((*token, self.view(frame, game_id, spectator.follow)));
+            }
+        }
+        let at = frame.at;
+        for (token, msg) in sent{
+            if let Some(spectator) = self.watchers.get_mut(&token){
+                spectator.last_frame = Some(at);
+                if let Some(sender) = spectator.update_sender.take(){
+                    _ = sender.send(msg);
+                }
+            }
+        }
+    }
+
+    //the current frame if the spectator has not seen it yet, otherwise wait for the next one
+    pub fn request(&mut self, token: u32, game_id: i32, now: time::Instant)->Result<Option<GameMessage>, oneshot::Receiver<GameMessage>>{
+
+        let (follow, last_frame) = match self.watchers.get_mut(&token){
+            Some(spectator)=>{
+                spectator.last_seen = now;
+                (spectator.follow, spectator.last_frame)
+            }
+            None=>return Ok(None),
+        };
+
+        if let Some(frame) = self.frame(now){
+            if last_frame != Some(frame.at){
+                let at = frame.at;
+                let msg = self.view(frame, game_id, follow);
+                if let Some(spectator) = self.watchers.get_mut(&token){
+                    spectator.last_frame = Some(at);
+                }
+                return Ok(Some(msg))
+            }
+        }
+
+        let (s, r) = oneshot::channel();
+        if let Some(spectator) = self.watchers.get_mut(&token){
+            spectator.update_sender = Some(s);
+        }
+        Err(r)
+    }
+
+    //the game is over, there is nothing left to hide
+    pub fn broadcast(&mut self, msg: GameMessage){
+        for spectator in self.watchers.values_mut(){
+            if let Some(sender) = spectator.update_sender.take(){
+                _ = sender.send(msg.clone());
+            }
+        }
+    }
+}
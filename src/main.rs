@@ -16,8 +16,14 @@ use rocket::http::Header;
 use rocket::{Request, Response};
 use rocket::fairing::{Fairing, Info, Kind};
 
-use island::handler::MatchMaker;
+use island::handler::{LobbyFilter, LobbyPage, MatchMaker};
+use island::matchmaking::QueueConfig;
+use island::reaper::ReaperConfig;
+use island::clock::SystemClock;
+use island::game::TICK;
 use island::game::Tile;
+use island::rules::{GameMode, GameRules};
+use island::pieces::{self, PieceConfig, PieceInfo};
 
 mod island;
 
@@ -54,20 +60,110 @@ async fn main()->Result<(), rocket::Error>{
 
     let api = database::get_api();
 
-    let bank:Bank = Arc::new( Mutex::new(MatchMaker::new()));
+    let rules : GameRules = rocket::Config::figment().extract_inner("rules").unwrap_or_else(|e|{
+        println!("using default rules: {}",e);
+        GameRules::default()
+    });
+
+    let extra_pieces : Vec<PieceConfig> = rocket::Config::figment().extract_inner("pieces").unwrap_or_default();
+    pieces::init(extra_pieces);
+
+    let queue : QueueConfig = rocket::Config::figment().extract_inner("matchmaking").unwrap_or_default();
+
+    let bank:Bank = Arc::new( Mutex::new(MatchMaker::new(rules, queue, Arc::new(SystemClock))));
+
+    let reaper : ReaperConfig = rocket::Config::figment().extract_inner("reaper").unwrap_or_default();
 
     let db:DB = Arc::new(api);
 
+    {
+        //the game loop, ticks every running game at a fixed rate and ends games that are over
+        let bank = bank.clone();
+        let db = db.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs_f32(TICK));
+            loop{
+                interval.tick().await;
+
+                let (ended, settlements) = match bank.lock(){
+                    Ok(mut mm)=>{
+                        let ended = mm.tick_games(TICK);
+                        (ended, mm.take_all_settlements())
+                    }
+                    _=>(vec![], vec![]),
+                };
+                settle(&db, settlements).await;
+                for game_id in ended{
+                    end_game(&bank, &db, game_id).await;
+                }
+            }
+        });
+    }
+
+    {
+        //the reaper, cleans up lobbies and games everybody walked away from
+        let bank = bank.clone();
+        let db = db.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs_f32(reaper.interval(TICK)));
+            loop{
+                interval.tick().await;
+
+                let (stale, settlements) = match bank.lock(){
+                    Ok(mut mm)=>{
+                        let stale = mm.reap(&reaper);
+                        (stale, mm.take_all_settlements())
+                    }
+                    _=>(vec![], vec![]),
+                };
+                settle(&db, settlements).await;
+                for game_id in stale{
+                    end_game(&bank, &db, game_id).await;
+                }
+            }
+        });
+    }
 
 
     let _rocket = rocket::build()
     .mount("/", routes![
         make_move,
+        drop_treasure,
+        end_turn,
+        queue_move,
+        cancel_queued,
+        reorder_queued,
+        get_pieces,
         register,
         login,
         join_game,
+        join_queue,
+        queue_party,
+        create_party,
+        invite_to_party,
+        accept_party,
+        leave_party,
+        promote_in_party,
+        party_status,
+        list_lobbies,
+        join_lobby,
+        create_private_lobby,
+        resume,
+        accept_rematch,
+        rematch_status,
+        current_game,
+        join_by_code,
+        kick_player,
+        queue_status,
+        leave_queue,
         get_update,
+        spectate,
+        spectate_by_code,
+        spectator_update,
+        follow_player,
+        stop_spectating,
         start_game,
+        set_ready,
         leave_game,
         leave_lobby,
         ])
@@ -141,6 +237,254 @@ async fn join_game(username: String, passhash: String, api: &State<DB>, bank: &S
 
 }
 
+#[get ("/api/lobbies?<mode>&<turn_based>&<max_stake>&<min_players>&<page>&<per_page>")]
+fn list_lobbies(
+    mode: Option<String>,
+    turn_based: Option<bool>,
+    max_stake: Option<i32>,
+    min_players: Option<i32>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    bank: &State<Bank>)-> Result<Json<LobbyPage>,NotFound<String>>{
+
+    let mode = match mode.as_deref(){
+        Some("Classic")=>Some(GameMode::Classic),
+        Some("ShrinkingIsland")=>Some(GameMode::ShrinkingIsland),
+        Some(other)=>return Err(NotFound(format!("unknown mode {}",other))),
+        None=>None,
+    };
+    let filter = LobbyFilter { mode, turn_based, max_stake, min_players };
+
+    let mm = bank.lock().or_else(|_|{
+        return Err(NotFound("cant get lock".to_string()))
+    })?;
+
+    Ok(Json(mm.list_lobbies(&filter, page.unwrap_or(0), per_page.unwrap_or(20))))
+}
+
+#[get ("/api/join_lobby/<game_id>/<username>/<passhash>")]
+async fn join_lobby(game_id: i32, username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let score = database::get_player_score(&username, &api.secret).await.or_else(|e|{Err(format!("cant get score {:?}",e))})?;
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.join_lobby(game_id, username, score).map(Json)
+}
+
+//back into the game the user was playing, after a reload or crash
+#[get ("/api/resume/<username>/<passhash>")]
+async fn resume(username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.resume(&username).map(Json)
+}
+
+//id of the game the user is in, without touching their token
+#[get ("/api/current_game/<username>/<passhash>")]
+async fn current_game(username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<i32>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.current_game(&username).map(Json).ok_or("not in a game".to_string())
+}
+
+//play the game that just ended again with whoever else wants to
+#[get ("/api/rematch/<game_id>/<username>/<passhash>")]
+async fn accept_rematch(game_id: i32, username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let score = database::get_player_score(&username, &api.secret).await.or_else(|e|{Err(format!("cant get score {:?}",e))})?;
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.accept_rematch(game_id, username, score).map(Json)
+}
+
+#[get ("/api/rematch_status/<game_id>/<username>/<passhash>")]
+async fn rematch_status(game_id: i32, username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.rematch_status(game_id, &username).map(Json)
+}
+
+#[get ("/api/create_private_lobby/<username>/<passhash>")]
+async fn create_private_lobby(username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let score = database::get_player_score(&username, &api.secret).await.or_else(|e|{Err(format!("cant get score {:?}",e))})?;
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.create_private_lobby(username, score).map(Json)
+}
+
+#[get ("/api/join_by_code/<code>/<username>/<passhash>")]
+async fn join_by_code(code: String, username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let score = database::get_player_score(&username, &api.secret).await.or_else(|e|{Err(format!("cant get score {:?}",e))})?;
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.join_by_code(username, score, code).map(Json)
+}
+
+#[get ("/api/kick/<game_id>/<player_token>/<number>")]
+fn kick_player(game_id: i32, player_token: u32, number: i32, bank: &State<Bank>)-> Result<(),NotFound<String>>{
+
+    let mut mm = bank.lock().or_else(|_|{
+        return Err(NotFound("cant get lock".to_string()))
+    })?;
+
+    mm.kick(game_id, player_token, number).map_err(NotFound)
+}
+
+#[get ("/api/join_queue/<username>/<passhash>")]
+async fn join_queue(username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let score = database::get_player_score(&username, &api.secret).await.or_else(|e|{Err(format!("cant get score {:?}",e))})?;
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.join_queue(username, score).map(Json)
+}
+
+//the party leader puts the whole party into the queue
+#[get ("/api/queue_party/<username>/<passhash>")]
+async fn queue_party(username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let names = {
+        let matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+        matchmaker.party_members(&username)?
+    };
+
+    let mut members = vec![];
+    for name in names{
+        let score = database::get_player_score(&name, &api.secret).await.or_else(|e|{Err(format!("cant get score {:?}",e))})?;
+        members.push((name, score));
+    }
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.queue_party(username, members).map(Json)
+}
+
+#[get ("/api/party/create/<username>/<passhash>")]
+async fn create_party(username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.create_party(username).map(Json)
+}
+
+#[get ("/api/party/invite/<username>/<passhash>/<friend>")]
+async fn invite_to_party(username: String, passhash: String, friend: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.invite_to_party(&username, friend).map(Json)
+}
+
+#[get ("/api/party/accept/<party_id>/<username>/<passhash>")]
+async fn accept_party(party_id: u32, username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.accept_party(username, party_id).map(Json)
+}
+
+#[get ("/api/party/leave/<username>/<passhash>")]
+async fn leave_party(username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.leave_party(&username).map(Json)
+}
+
+//hand the lead to another member
+#[get ("/api/party/promote/<username>/<passhash>/<member>")]
+async fn promote_in_party(username: String, passhash: String, member: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.promote_in_party(&username, &member).map(Json)
+}
+
+//the party, or the join message once the leader got everyone a game
+#[get ("/api/party_status/<username>/<passhash>")]
+async fn party_status(username: String, passhash: String, api: &State<DB>, bank: &State<Bank>)-> Result<Json<GameMessage>,String>{
+
+    if ! database::check_user_credentials(&username, &passhash, &api.secret).await?{
+        return Err("authentication failed.".to_string())
+    }
+
+    let mut matchmaker = bank.lock().or_else(|e|{Err(format!("error getting matchmaker {}",e.to_string()))})?;
+    matchmaker.party_status(&username).map(Json)
+}
+
+//queue position while waiting, the join message once a lobby was found
+#[get ("/api/queue_status/<ticket>")]
+fn queue_status(ticket: u32, bank: &State<Bank>)-> Result<Json<GameMessage>,NotFound<String>>{
+
+    let mut mm = bank.lock().or_else(|_|{
+        return Err(NotFound("cant get lock".to_string()))
+    })?;
+
+    mm.queue_status(ticket).map(Json).map_err(NotFound)
+}
+
+#[get ("/api/leave_queue/<ticket>")]
+fn leave_queue(ticket: u32, bank: &State<Bank>)-> Result<(),NotFound<String>>{
+
+    let mut mm = bank.lock().or_else(|_|{
+        return Err(NotFound("cant get lock".to_string()))
+    })?;
+
+    mm.leave_queue(ticket).map_err(NotFound)
+}
+
 #[get ("/api/leave_lobby/<game_id>/<player_token>")]
 async fn leave_lobby(game_id: i32, player_token:u32, bank: &State<Bank>) -> Result<(),NotFound<String>>{
 
@@ -169,9 +513,9 @@ async fn leave_game(game_id:i32, player_token:u32,bank : &State<Bank>,api: &Stat
         GameMessage::Leave { name, value }=>{
             database::set_player_score(&name, *value, &api.secret).await?;
         }
-        GameMessage::End { winning_number,value:_} =>{
+        GameMessage::End { .. } =>{
             //end game
-            end_game(bank, api, game_id, *winning_number).await;
+            end_game(bank, api, game_id).await;
             
         }
         msg=>{
@@ -182,7 +526,15 @@ async fn leave_game(game_id:i32, player_token:u32,bank : &State<Bank>,api: &Stat
     Ok(Json(res))
 }
 
-async fn end_game(bank: &State<Bank>,api:&State<DB>, game_id:i32,winning_number: i32){
+async fn settle(api:&DB, settlements: Vec<(String,i32)>){
+    for (name, value) in settlements{
+        if let Err(e) = database::set_player_score(&name, value, &api.secret).await{
+            println!("error settling score of {} {}",name,e.0);
+        }
+    }
+}
+
+async fn end_game(bank: &Bank,api:&DB, game_id:i32){
     let g = match bank.lock(){
         Ok(mut mm)=>{
             mm.take_game(game_id)
@@ -191,7 +543,7 @@ async fn end_game(bank: &State<Bank>,api:&State<DB>, game_id:i32,winning_number:
     };
 
     if let Some(mut game) = g{
-        game.end(&api.secret,winning_number).await
+        game.end(&api.secret).await
     }
 }
 
@@ -202,23 +554,123 @@ pub enum GameMessage{
         number: i32,
         token: u32,
     },
+    //the join message of whoever created a private lobby
+    Hosted{
+        code: String,
+        game_id: i32,
+        number: i32,
+        token: u32,
+    },
+    Kicked{
+        game_id: i32,
+    },
+    //the party of a user and the parties that invited them, party_id is 0 if they are in none
+    Party{
+        party_id: u32,
+        leader: String,
+        members: Vec<String>,
+        invited: Vec<String>,
+        invites: Vec<u32>,
+    },
+    //who took the rematch so far, until the new lobby is there
+    Rematch{
+        game_id: i32,
+        accepted: Vec<String>,
+        roster: Vec<String>,
+        time_left: f32,
+    },
+    //the spectator token for a game
+    Watching{
+        game_id: i32,
+        token: u32,
+        delay: f32,
+    },
+    //the board as it was delay seconds ago, all of it or what the followed player saw
+    Spectate{
+        game_id: i32,
+        data: Vec<(i32, Tile)>,
+        follow: i32,
+        players: Vec<(String, i32)>,
+        delay: f32,
+        spectators: i32,
+    },
+    //nobody was playing anymore, what everyone got back as player number and score
+    Abandoned{
+        game_id: i32,
+        shares: Vec<(i32, i32)>,
+    },
+    Queued{
+        ticket: u32,
+        position: i32,
+        waited: f32,
+        //seconds, -1 until the queue has placed anyone
+        estimated_wait: f32,
+    },
     Lobby{
         players:Vec<(String, i32)>,
+        //number of the host of a private lobby, -1 for public ones
+        host: i32,
+        code: Option<String>,
+        //numbers of the players that are ready
+        ready: Vec<i32>,
+        //seconds until the game starts by itself, -1 if not counting down
+        countdown: f32,
     },
     State{
-        data: Vec<Option<Tile>>,
+        //every tile the player can see as board index and tile
+        data: Vec<(i32, Tile)>,
+        //tiles out of sight as the player last saw them, with the seconds since then
+        remembered: Vec<(i32, Tile, f32)>,
+        //position of the king
         offset: (i32,i32),
         energy: f32,
+        //seconds until each of the players pieces that is still cooling down can act, by board index
+        cooldowns: Vec<(i32, f32)>,
+        //queued moves as id, start, end, spawn and the energy they wait for
+        queue: Vec<(i32, i32, i32, i32, f32)>,
         got_treasure : bool,
         treasure_holder : i32,
+        banked : i32,
+        time_left : f32,
+        sudden_death : bool,
+        //turn based games only, -1 otherwise
+        round : i32,
+        round_left : f32,
+        planned : i32,
+        //numbers of players whose client stopped polling
+        disconnected : Vec<i32>,
+        //how many people are watching the game
+        spectators : i32,
+        treasures : Vec<(i32,i32)>,
+        //seconds until the next ring floods and which ring it is, -1 if none
+        flood_in : f32,
+        flood_ring : i32,
     },
     Leave{
         name: String,
         value: i32,
     },
+    Eliminated{
+        name: String,
+        number: i32,
+    },
     End{
         winning_number: i32,
         value: i32,
+        //everyone who gets a share of the pot
+        winners: Vec<i32>,
+    },
+    Split{
+        shares: Vec<(i32, i32)>,
+    },
+    //a queued move could not be made when its turn came
+    QueueDropped{
+        id: i32,
+    },
+    //the moves of a turn based round as player number, start, end, spawn and whether it happened
+    Round{
+        round: i32,
+        results: Vec<(i32, i32, i32, i32, bool)>,
     }
 }
 
@@ -277,6 +729,68 @@ async fn start_game(game_id:i32, player_token:u32,bank : &State<Bank>) -> String
     }
 }
 
+#[get("/api/ready/<game_id>/<player_token>/<ready>")]
+fn set_ready(game_id:i32, player_token:u32, ready:bool, bank : &State<Bank>) -> String {
+    let mut mm = bank.lock().expect("cannot get bank lock");
+    if let Err(msg) = mm.set_ready(game_id, player_token, ready){
+        msg
+    }else{
+        "Ok".to_string()
+    }
+}
+
+#[get("/api/spectate/<game_id>?<follow>")]
+fn spectate(game_id:i32, follow:Option<i32>, bank:&State<Bank>)->Result<Json<GameMessage>,NotFound<String>>{
+    let mut mm = bank.lock().or_else(|_|{
+        return Err(NotFound("cant get lock".to_string()))
+    })?;
+    mm.spectate(game_id, follow.unwrap_or(-1)).map(Json).map_err(NotFound)
+}
+
+#[get("/api/spectate_code/<code>?<follow>")]
+fn spectate_by_code(code:String, follow:Option<i32>, bank:&State<Bank>)->Result<Json<GameMessage>,NotFound<String>>{
+    let mut mm = bank.lock().or_else(|_|{
+        return Err(NotFound("cant get lock".to_string()))
+    })?;
+    mm.spectate_by_code(code, follow.unwrap_or(-1)).map(Json).map_err(NotFound)
+}
+
+#[get("/api/follow/<game_id>/<spectator_token>/<number>")]
+fn follow_player(game_id:i32, spectator_token:u32, number:i32, bank:&State<Bank>)->Json<bool>{
+    match bank.lock(){
+        Ok(mut mm)=>Json(mm.follow(game_id, spectator_token, number)),
+        _=>Json(false),
+    }
+}
+
+#[get("/api/stop_spectating/<game_id>/<spectator_token>")]
+fn stop_spectating(game_id:i32, spectator_token:u32, bank:&State<Bank>)->Json<bool>{
+    match bank.lock(){
+        Ok(mut mm)=>Json(mm.stop_spectating(game_id, spectator_token)),
+        _=>Json(false),
+    }
+}
+
+#[get("/api/spectator_update/<game_id>/<spectator_token>")]
+async fn spectator_update(game_id:i32, spectator_token:u32, bank:&State<Bank>) -> Result<Json<GameMessage>, NotFound<String>>{
+
+    let res = {
+        let mut mm = bank.lock().or_else(|_|{
+            return Err(NotFound("cant get lock".to_string()))
+        })?;
+
+        mm.spectator_update(game_id, spectator_token)?
+    };
+
+    let listener = match res{
+        Ok(Some(gmsg))=>return Ok(Json(gmsg)),
+        Ok(None)=>return Err(NotFound("cant find spectator".to_string())),
+        Err(rec)=>rec,
+    };
+
+    listener.await.map(Json).map_err(|_| NotFound("cant find update".into()))
+}
+
 #[derive(Serialize)]
 pub enum MoveResult{
     Fail,
@@ -296,21 +810,24 @@ async fn make_move(
     api: &State<DB>,
     bank:&State<Bank>)->Json<MoveResult>{
 
-    let res = match bank.lock(){
+    let (res, settlements) = match bank.lock(){
         Ok(mut mm)=>{
 
-            mm.make_move(game_id, player_token, start, end, spawn)
+            let res = mm.make_move(game_id, player_token, start, end, spawn);
+            (res, mm.take_settlements(game_id))
 
         }
         _=>{
-            MoveResult::Fail
+            (MoveResult::Fail, vec![])
         }
     };
 
+    settle(&api, settlements).await;
+
     match res{
-        MoveResult::End{winner: winning_number}=>{
+        MoveResult::End{winner: _}=>{
 
-            end_game(bank, api, game_id, winning_number).await;
+            end_game(bank, api, game_id).await;
         }
         _=>{}
     }
@@ -318,3 +835,72 @@ async fn make_move(
     Json(res)
 }
 
+#[post("/api/queue_move/<game_id>/<player_token>/<start>/<end>/<spawn>?<min_energy>")]
+fn queue_move(game_id:i32, player_token:u32, start:i32, end:i32, spawn:i32, min_energy:Option<f32>, bank:&State<Bank>)->Json<Option<i32>>{
+    match bank.lock(){
+        Ok(mut mm)=>Json(mm.queue_move(game_id, player_token, start, end, spawn, min_energy.unwrap_or(0.))),
+        _=>Json(None),
+    }
+}
+
+#[post("/api/cancel_queued/<game_id>/<player_token>/<move_id>")]
+fn cancel_queued(game_id:i32, player_token:u32, move_id:i32, bank:&State<Bank>)->Json<MoveResult>{
+    match bank.lock(){
+        Ok(mut mm)=>Json(mm.cancel_queued(game_id, player_token, move_id)),
+        _=>Json(MoveResult::Fail),
+    }
+}
+
+#[post("/api/reorder_queued/<game_id>/<player_token>/<move_id>/<index>")]
+fn reorder_queued(game_id:i32, player_token:u32, move_id:i32, index:usize, bank:&State<Bank>)->Json<MoveResult>{
+    match bank.lock(){
+        Ok(mut mm)=>Json(mm.reorder_queued(game_id, player_token, move_id, index)),
+        _=>Json(MoveResult::Fail),
+    }
+}
+
+#[post("/api/end_turn/<game_id>/<player_token>")]
+async fn end_turn(game_id:i32, player_token:u32, api: &State<DB>, bank:&State<Bank>)->Json<MoveResult>{
+
+    let (res, settlements) = match bank.lock(){
+        Ok(mut mm)=>{
+            let res = mm.end_turn(game_id, player_token);
+            (res, mm.take_settlements(game_id))
+        }
+        _=>{
+            (MoveResult::Fail, vec![])
+        }
+    };
+
+    settle(&api, settlements).await;
+
+    if let MoveResult::End{winner: _} = res{
+        end_game(bank, api, game_id).await;
+    }
+
+    Json(res)
+}
+
+#[post("/api/drop_treasure/<game_id>/<player_token>/<target>")]
+async fn drop_treasure(game_id:i32, player_token:u32, target:i32, bank:&State<Bank>)->Json<MoveResult>{
+
+    let res = match bank.lock(){
+        Ok(mut mm)=>{
+            mm.drop_treasure(game_id, player_token, target)
+        }
+        _=>{
+            MoveResult::Fail
+        }
+    };
+
+    Json(res)
+}
+
+#[get("/api/pieces")]
+async fn get_pieces(bank:&State<Bank>)->Json<Vec<PieceInfo>>{
+    let water = match bank.lock(){
+        Ok(mm)=>mm.rules().mode.has_water(),
+        _=>false,
+    };
+    Json(pieces::registry().catalogue(water))
+}